@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use text_editor::Key;
+
+// Raw terminal input, as typed or pasted
+fuzz_target!(|data: &[u8]| {
+    let mut input = data;
+    while Key::decode(&mut input).is_ok() {}
+});
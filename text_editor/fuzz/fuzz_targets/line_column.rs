@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use text_editor::buffer::{self, index_to_line_column};
+
+fuzz_target!(|input: (&str, usize)| {
+    let (text, index) = input;
+    if let Some((line, col)) = index_to_line_column(text, index) {
+        assert_eq!(buffer::offset(&buffer::lines(text), line, col), index);
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use text_editor::protocol::WsMessage;
+use text_editor::terminal::Headless;
+use text_editor::{Editor, Key};
+
+// A hosted document, then whatever a peer sends about it, then the host
+// typing on: nothing a peer sends may panic the host
+fuzz_target!(|input: (&str, &str, Vec<char>)| {
+    let (text, message, typed) = input;
+    let mut editor = Editor::with_terminal(None, Arc::new(Headless::new(10, 20))).unwrap();
+    editor.set_text(text);
+
+    if let Some(WsMessage::Change { revision, changes }) = WsMessage::parse(message, 0) {
+        if editor.apply_remote_change(1, revision, &changes).is_err() {
+            assert_eq!(editor.text(), text);
+        }
+    }
+    for key in [Key::Left, Key::Up, Key::End, Key::Right, Key::Backspace] {
+        editor.process_keypress(&key).unwrap();
+    }
+    for c in typed {
+        editor.process_keypress(&Key::Char(c)).unwrap();
+    }
+    editor.refresh_screen().unwrap();
+});
@@ -0,0 +1,42 @@
+// The document as the editor holds it: one string per line, without the
+// line endings
+
+pub fn lines(text: &str) -> Vec<String> {
+    text.split('\n').map(|s| s.to_string()).collect()
+}
+
+pub fn text(lines: &[String]) -> String {
+    lines.join("\n")
+}
+
+// Byte offset of a line and column in the joined text
+pub fn offset(lines: &[String], line: usize, col: usize) -> usize {
+    lines.iter().take(line).map(|s| s.len() + 1).sum::<usize>() + col
+}
+
+// Line and byte column of a byte offset in the joined text, or `None` when
+// the offset is past the end or inside a character
+pub fn index_to_line_column(s: &str, index: usize) -> Option<(usize, usize)> {
+    if !s.is_char_boundary(index) {
+        return None;
+    }
+
+    let before = &s[..index];
+    let line = before.matches('\n').count();
+    let col = before.rfind('\n').map_or(index, |newline| index - newline - 1);
+    Some((line, col))
+}
+
+// The closest character boundary at or before `index`, for slicing lines at
+// columns that may fall inside a character
+pub fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    (0..=index).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0)
+}
+
+// The byte offset where the character before `index` starts
+pub fn previous_char_boundary(s: &str, index: usize) -> usize {
+    floor_char_boundary(s, index.saturating_sub(1))
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::editor::Editor;
+use crate::limits::{Limits, DEFAULT_MAX_DOCUMENT_SIZE, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MESSAGES_PER_SECOND};
+
+// Command line options for the editor and its collaboration server
+#[derive(Parser, Debug)]
+#[command(name = "text_editor", version, about = "A collaborative terminal text editor", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// File to open
+    pub filename: Option<String>,
+
+    /// Do not start the collaboration server
+    #[arg(long, conflicts_with_all = ["tls_cert", "tls_self_signed"])]
+    pub no_server: bool,
+
+    /// Join the session hosted by another editor, e.g. ws://host:3030/ws
+    #[arg(long, value_name = "URL", conflicts_with_all = ["filename", "tls_cert", "tls_self_signed", "edit_token", "op_log"])]
+    pub join: Option<String>,
+
+    /// Join as a viewer, watching the session without editing
+    #[arg(long, requires = "join")]
+    pub viewer: bool,
+
+    /// Name shown to the others next to your cursor (defaults to $USER)
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// PEM certificate to trust when joining a wss:// session
+    #[arg(long, requires = "join")]
+    pub tls_ca: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub host: HostArgs,
+}
+
+// Options of an editor hosting a document, with or without a terminal UI
+#[derive(Args, Debug)]
+pub struct HostArgs {
+    /// Port for the collaboration server (0 picks a free port)
+    #[arg(short, long, default_value_t = 3030)]
+    pub port: u16,
+
+    /// Address the collaboration server binds to
+    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub bind: IpAddr,
+
+    /// Only let clients that present this token (?token=...) edit; everyone else may only watch
+    #[arg(long, value_name = "TOKEN")]
+    pub edit_token: Option<String>,
+
+    /// Open the document read-only, rejecting local and remote edits
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Keep the previous version of the file as FILE~ when saving
+    #[arg(long)]
+    pub backup: bool,
+
+    /// Save the file after this many seconds without edits
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub autosave: Option<u64>,
+
+    /// Do not keep a swap file of unsaved changes for crash recovery
+    #[arg(long)]
+    pub no_swap: bool,
+
+    /// Write the log to this file, or to stderr with - when serving [default: text_editor.log in $XDG_STATE_HOME/text_editor]
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Minimum level of messages written to the log
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Start a new log file every day or hour, keeping the last few [default: daily for the default log file, else never]
+    #[arg(long, value_enum)]
+    pub log_rotation: Option<LogRotation>,
+
+    /// PEM certificate chain for serving wss://
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for serving wss://
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve wss:// with a generated self-signed development certificate
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub tls_self_signed: bool,
+
+    /// Record every change to this file as JSON lines, for `replay`
+    #[arg(long, value_name = "FILE")]
+    pub op_log: Option<PathBuf>,
+
+    /// Largest message a client may send, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    pub max_message_size: usize,
+
+    /// Largest the document may grow to through changes from clients, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_DOCUMENT_SIZE)]
+    pub max_document_size: usize,
+
+    /// Messages per second each client may send before it is slowed down
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MESSAGES_PER_SECOND, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: u32,
+}
+
+impl HostArgs {
+    // Apply the options about the document to `editor`
+    pub fn configure(&self, editor: &mut Editor) -> io::Result<()> {
+        editor.read_only = self.read_only;
+        editor.backup = self.backup;
+        editor.swap = !self.no_swap;
+        editor.autosave = self.autosave.map(Duration::from_secs);
+        editor.edit_token = self.edit_token.clone();
+        editor.limits = self.limits();
+        if let Some(path) = &self.op_log {
+            editor
+                .start_op_log(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to create op log {}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits { max_message_size: self.max_message_size, max_document_size: self.max_document_size, messages_per_second: self.rate_limit }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Rebuild a document from an op log written with --op-log
+    Replay(ReplayArgs),
+    /// Serve a file to remote clients without a terminal UI, until SIGTERM
+    Serve(ServeArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Op log to read
+    pub log: PathBuf,
+
+    /// Revision to rebuild (defaults to the latest)
+    #[arg(short, long)]
+    pub revision: Option<u64>,
+
+    /// Session of the log to read, counting from 1 each time a host started
+    /// logging (defaults to the latest, or every session with --history)
+    #[arg(short, long)]
+    pub session: Option<usize>,
+
+    /// List who changed what and when instead of printing the document
+    #[arg(long)]
+    pub history: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// File to serve, created on the first save if it does not exist
+    pub filename: String,
+
+    #[command(flatten)]
+    pub host: HostArgs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+use tracing::debug;
+use url::Url;
+
+use crate::hub::Hub;
+use crate::protocol::{WsMessage, HEARTBEAT_INTERVAL, PEER_TIMEOUT};
+use crate::session::{GuestSession, Session};
+
+type HostStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Delay before the first reconnection attempt, doubled after every failure
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// How long to keep reading from a connection that can no longer be written to
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Join the session hosted by another editor at `url` (ws:// or wss://).
+// Returns once the host's document has been loaded into the editor behind
+// `hub`; the connection keeps it in sync from a background task afterwards,
+// and is re-established if it drops.
+pub async fn join(url: &str, hub: Hub, connector: Option<Connector>) -> Result<(), Box<dyn std::error::Error>> {
+    let url = Url::parse(url)?;
+    let client_id = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let mut ws = connect(&url, &client_id, None, connector.clone()).await?;
+
+    // The host always starts with a snapshot of the document
+    let (text, revision) = loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => {
+                if let Ok(WsMessage::Snapshot { text, revision }) = serde_json::from_str(&text) {
+                    break (text, revision);
+                }
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err("connection closed before the document was received".into()),
+        }
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<WsMessage>();
+    hub.with(move |editor| {
+        editor.session = Session::Guest(GuestSession::new(revision, tx));
+        editor.handle_host_message(WsMessage::Snapshot { text, revision })
+    })
+    .await?;
+
+    tokio::spawn(async move {
+        loop {
+            sync(ws, &hub, &mut rx).await;
+
+            let mut delay = RECONNECT_DELAY;
+            ws = loop {
+                let status = format!("Disconnected from host, reconnecting in {:.1}s", delay.as_secs_f64());
+                let revision = hub
+                    .with(move |editor| {
+                        let Session::Guest(guest) = &mut editor.session else {
+                            return None;
+                        };
+                        guest.disconnected();
+                        let revision = guest.revision();
+                        editor.status_message = status;
+                        Some(revision)
+                    })
+                    .await;
+                let Some(revision) = revision else {
+                    return;
+                };
+                tokio::time::sleep(delay).await;
+
+                // Whatever was queued for the old connection is resent once the host confirms the resume
+                let held: Vec<WsMessage> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+                if !held.is_empty() {
+                    hub.with(move |editor| {
+                        if let Session::Guest(guest) = &mut editor.session {
+                            held.into_iter().for_each(|message| guest.hold(message));
+                        }
+                    })
+                    .await;
+                }
+                match connect(&url, &client_id, Some(revision), connector.clone()).await {
+                    Ok(ws) => break ws,
+                    Err(e) => {
+                        debug!("Reconnecting to {} failed: {}", url, e);
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            };
+
+            let status = format!("Reconnected to {}", url);
+            hub.with(move |editor| editor.status_message = status).await;
+        }
+    });
+
+    Ok(())
+}
+
+async fn connect(url: &Url, client_id: &str, revision: Option<u64>, connector: Option<Connector>) -> Result<HostStream, tokio_tungstenite::tungstenite::Error> {
+    let mut url = url.clone();
+    url.query_pairs_mut().append_pair("client", client_id);
+    if let Some(revision) = revision {
+        url.query_pairs_mut().append_pair("revision", &revision.to_string());
+    }
+    let (ws, _) = connect_async_tls_with_config(url.as_str(), None, false, connector).await?;
+    Ok(ws)
+}
+
+// Exchange messages with the host until the connection closes or goes quiet
+async fn sync(ws: HostStream, hub: &Hub, rx: &mut UnboundedReceiver<WsMessage>) {
+    let (mut sink, mut stream) = ws.split();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(Ok(msg)) = msg else {
+                    return;
+                };
+                last_seen = Instant::now();
+                if !receive(hub, msg).await {
+                    return;
+                }
+            }
+            Some(message) = rx.recv() => {
+                let Ok(text) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > PEER_TIMEOUT {
+                    debug!("Host timed out");
+                    return;
+                }
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Sending fails as soon as the host has closed the connection, but what it
+    // sent before that, such as why it left, can still be read
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while let Some(Ok(msg)) = stream.next().await {
+            if !receive(hub, msg).await {
+                return;
+            }
+        }
+    })
+    .await;
+}
+
+// Handle a message from the host. Returns false once the session is over.
+async fn receive(hub: &Hub, msg: Message) -> bool {
+    let Message::Text(text) = msg else {
+        return true;
+    };
+    let Ok(message) = serde_json::from_str::<WsMessage>(&text) else {
+        debug!("Ignoring message from host: {}", text);
+        return true;
+    };
+
+    hub.with(move |editor| {
+        if let Err(e) = editor.handle_host_message(message) {
+            editor.status_message = format!("Out of sync with host: {}", e);
+        }
+        // The host ended the session, there is nothing to reconnect to
+        matches!(editor.session, Session::Guest(_))
+    })
+    .await
+}
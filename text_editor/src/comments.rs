@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use crate::ot::Operation;
+
+// A note attached to a line of the document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub author: String,
+    pub text: String,
+    // Byte offset of the start of the line when the comment was made. It moves
+    // with the text like a cursor, so the comment stays on that line.
+    pub offset: usize,
+    // Client id of the peer that made it, known only to the host, so that
+    // peer may resolve it even as a viewer
+    pub client: Option<String>,
+}
+
+// The session's comments by the id the host gave them
+#[derive(Default)]
+pub struct Comments {
+    comments: BTreeMap<u64, Comment>,
+    next_id: u64,
+}
+
+impl Comments {
+    // Add a new comment, returning its id. Only the host hands out ids.
+    pub fn add(&mut self, comment: Comment) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.comments.insert(id, comment);
+        id
+    }
+
+    // Store a comment the host announced
+    pub fn insert(&mut self, id: u64, comment: Comment) {
+        self.comments.insert(id, comment);
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Comment> {
+        self.comments.get(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Comment> {
+        self.comments.remove(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.comments.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Comment)> {
+        self.comments.iter().map(|(id, comment)| (*id, comment))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.comments.is_empty()
+    }
+
+    // Ids of the comments on the line spanning `start..=end`
+    pub fn on_line(&self, start: usize, end: usize) -> Vec<u64> {
+        self.iter()
+            .filter(|(_, comment)| (start..=end).contains(&comment.offset))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    pub fn transform(&mut self, op: &Operation) {
+        for comment in self.comments.values_mut() {
+            comment.offset = op.transform_index(comment.offset);
+        }
+    }
+}
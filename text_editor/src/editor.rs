@@ -0,0 +1,1621 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use crate::buffer::{self, index_to_line_column};
+use crate::comments::{Comment, Comments};
+use crate::file::{self, FileFormat, Stamp};
+use crate::hub::{Hub, Tick};
+use crate::limits::Limits;
+use crate::metrics::Metrics;
+use crate::ot::{Operation, OtError};
+use crate::presence::Presence;
+use crate::prompt::{Prompt, PromptEvent, PromptHistory, PromptKind};
+use crate::protocol::{ConnectParams, Role, TextChange, WsMessage};
+use crate::session::{HostSession, Outbox, PeerId, Resync, Session};
+use crate::terminal::{self, Backend, Key, Tty};
+
+fn comment_message(id: u64, comment: &Comment) -> WsMessage {
+    WsMessage::Comment { id, author: comment.author.clone(), offset: comment.offset, text: comment.text.clone() }
+}
+
+// Remove a file that may already be gone
+fn remove_file(path: &Path) {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => debug!("Failed to remove {}: {}", path.display(), e),
+        _ => {}
+    }
+}
+
+// Presence id of the hosting editor's own cursor
+const HOST_ID: &str = "host";
+const DISK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SWAP_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Editor {
+    pub content: Vec<String>,
+    pub cursor_x: usize,
+    pub cursor_y: usize,
+    pub offset_x: usize,
+    pub offset_y: usize,
+    pub terminal_rows: usize,
+    pub terminal_cols: usize,
+    pub filename: Option<String>,
+    pub modified: bool,
+    pub read_only: bool,
+    // Line ending and final newline of the file on disk
+    pub format: FileFormat,
+    // Keep the previous version as `filename~` when saving
+    pub backup: bool,
+    // The file as last loaded or saved, the common ancestor when merging
+    // changes made to it by other programs
+    disk: DiskState,
+    external_change: Option<ExternalChange>,
+    // Crash recovery: keep a swap file of unsaved changes, and optionally
+    // save by itself once editing pauses
+    pub swap: bool,
+    pub autosave: Option<Duration>,
+    // Bumped on every change to the document, and when that last happened
+    edits: u64,
+    last_edit: Instant,
+    swapped_edits: u64,
+    // The swap file this editor wrote, the only one it may remove unasked
+    swap_written: Option<PathBuf>,
+    quit_state: QuitState,
+    pub shutdown: Shutdown,
+    pub status_message: String,
+    // Minibuffer input replacing the status message while it is open
+    pub prompt: Option<Prompt>,
+    prompt_history: PromptHistory,
+    pub session: Session,
+    // Length of the document as last described to the session
+    published_len: usize,
+    // Who we are to the others in the session, and where they are
+    pub name: String,
+    pub presence: Presence,
+    published_cursor: Option<usize>,
+    // Presence id of the collaborator whose cursor the viewport tracks
+    pub following: Option<String>,
+    pub comments: Comments,
+    // Peers that do not present this token may only watch
+    pub edit_token: Option<String>,
+    // What each peer may send, and counts of what was turned away
+    pub limits: Limits,
+    pub metrics: Arc<Metrics>,
+    // Nobody at the keyboard: questions get a safe default answer, and there
+    // is no cursor of our own to show the others
+    pub unattended: bool,
+    terminal: Arc<dyn Backend>,
+}
+
+impl Editor {
+    pub fn new(filename: Option<String>) -> io::Result<Self> {
+        Self::with_terminal(filename, Arc::new(Tty))
+    }
+
+    // An editor drawing to and reading keys from `terminal` instead of the TTY
+    pub fn with_terminal(filename: Option<String>, terminal: Arc<dyn Backend>) -> io::Result<Self> {
+        let (rows, cols) = terminal.size()?;
+
+        // A file that does not exist yet is created on save, one that cannot
+        // be read must not be replaced by an empty buffer
+        let (content, format) = match filename.as_ref().map(|fname| (fname, file::read(Path::new(fname)))) {
+            Some((_, Ok(file))) => file,
+            Some((_, Err(e))) if e.kind() == io::ErrorKind::NotFound => (vec![String::new()], FileFormat::default()),
+            Some((fname, Err(e))) => return Err(io::Error::new(e.kind(), format!("cannot open {}: {}", fname, e))),
+            None => (vec![String::new()], FileFormat::default()),
+        };
+        let disk = DiskState {
+            stamp: filename.as_ref().and_then(|fname| file::stamp(Path::new(fname)).ok().flatten()),
+            text: content.join("\n"),
+        };
+        let published_len = content.join("\n").len();
+
+        Ok(Editor {
+            content,
+            cursor_x: 0,
+            cursor_y: 0,
+            offset_x: 0,
+            offset_y: 0,
+            terminal_rows: rows.saturating_sub(2), // Reserve space for status bar
+            terminal_cols: cols,
+            filename,
+            modified: false,
+            read_only: false,
+            format,
+            backup: false,
+            disk,
+            external_change: None,
+            swap: true,
+            autosave: None,
+            edits: 0,
+            last_edit: Instant::now(),
+            swapped_edits: 0,
+            swap_written: None,
+            quit_state: QuitState::Editing,
+            shutdown: Shutdown::default(),
+            status_message: "Press Ctrl+Q to quit, Ctrl+S to save, Ctrl+H for help".to_string(),
+            prompt: None,
+            prompt_history: PromptHistory::default(),
+            session: Session::Host(HostSession::default()),
+            published_len,
+            name: std::env::var("USER").unwrap_or_else(|_| HOST_ID.to_string()),
+            presence: Presence::default(),
+            // Where new peers are told it is
+            published_cursor: Some(0),
+            following: None,
+            comments: Comments::default(),
+            edit_token: None,
+            limits: Limits::default(),
+            metrics: Arc::default(),
+            unattended: false,
+            terminal,
+        })
+    }
+
+    // Tell the session about the edit `key` just made. Insertions report the
+    // cursor after the new text, deletions the position of the removed text.
+    pub fn broadcast_change(&mut self, key: &Key, start: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let change = match key {
+            Key::Char(_) | Key::Enter => {
+                let text = if let Key::Char(c) = key { c.to_string() } else { "\n".to_string() };
+                let start = start.saturating_sub(text.len());
+                TextChange { text, start, end: start }
+            }
+            // Whatever the key removed, a character of several bytes or a line break
+            Key::Backspace | Key::Delete => {
+                let removed = self.published_len.saturating_sub(self.text().len());
+                TextChange { text: String::new(), start, end: start + removed }
+            }
+            _ => return Ok(()),
+        };
+
+        debug!("Broadcasting change: {:?}", change);
+        self.publish(&[change]);
+        Ok(())
+    }
+
+    // Hand a local change to the session so it reaches the other editors
+    pub fn publish(&mut self, changes: &[TextChange]) {
+        let text = self.text();
+        let op = Operation::from_changes(self.published_len, changes).ok().filter(|op| op.target_len() == text.len());
+        debug_assert!(op.is_some(), "change {:?} does not match the document", changes);
+        // Sending it whole keeps everyone in step, but loses concurrent edits
+        let op = op.unwrap_or_else(|| {
+            warn!(?changes, "change does not match the document, sending it whole");
+            Operation::replace_all(self.published_len, &text)
+        });
+        self.published_len = op.target_len();
+        self.presence.transform(&op);
+        self.comments.transform(&op);
+        self.touch();
+
+        match &mut self.session {
+            Session::Host(host) => {
+                host.commit(op, None);
+                self.metrics.op_committed(Instant::now());
+                self.update_metrics();
+            }
+            Session::Guest(guest) => guest.local(op),
+        }
+        self.publish_cursor();
+    }
+
+    pub fn text(&self) -> String {
+        buffer::text(&self.content)
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.content = buffer::lines(text);
+        self.published_len = text.len();
+    }
+
+    pub fn cursor_offset(&self) -> usize {
+        buffer::offset(&self.content, self.cursor_y, self.cursor_x)
+    }
+
+    fn set_cursor_offset(&mut self, offset: usize) {
+        let text = self.text();
+        let (line, col) = index_to_line_column(&text, offset.min(text.len()))
+            .unwrap_or((self.content.len() - 1, self.content[self.content.len() - 1].len()));
+        self.cursor_y = line;
+        self.cursor_x = col;
+        self.scroll();
+    }
+
+    // Apply a change made by another editor, keeping the cursor on the same text
+    fn apply_operation(&mut self, op: &Operation) -> Result<(), OtError> {
+        let text = op.apply(&self.text())?;
+        let cursor = op.transform_index(self.cursor_offset());
+        self.presence.transform(op);
+        self.comments.transform(op);
+        self.set_text(&text);
+        self.set_cursor_offset(cursor);
+        self.modified = true;
+        self.touch();
+        Ok(())
+    }
+
+    fn touch(&mut self) {
+        self.edits += 1;
+        self.last_edit = Instant::now();
+    }
+
+    // Register a connected peer and bring it up to date: a returning client is
+    // sent the changes it missed, anyone else the whole document
+    pub fn add_peer(&mut self, outbox: Outbox, params: ConnectParams) -> Option<PeerId> {
+        let text = self.text();
+        let Session::Host(host) = &mut self.session else {
+            return None;
+        };
+        // Editing takes the host's token when it has one; nobody edits a
+        // read-only document
+        let role = match params.role.unwrap_or_default() {
+            Role::Editor if !self.read_only && (self.edit_token.is_none() || params.token == self.edit_token) => Role::Editor,
+            _ => Role::Viewer,
+        };
+        let resume = params.revision.filter(|_| params.client.is_some());
+        let peer = host.add_peer(outbox, params.client);
+        host.set_identity(peer, params.name, role);
+        let resumed = resume.is_some_and(|revision| host.resume_peer(peer, revision));
+        if !resumed {
+            host.send_to(peer, WsMessage::Snapshot { text, revision: host.revision() });
+        }
+        host.send_to(peer, WsMessage::Joined { role, id: peer.to_string() });
+        info!(%peer, name = host.name(peer).unwrap_or_default(), %role, resumed, "peer joined");
+        self.send_session_state(peer);
+        self.update_metrics();
+        Some(peer)
+    }
+
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        if let Session::Host(host) = &mut self.session {
+            host.remove_peer(peer);
+            host.broadcast(WsMessage::Departed { id: peer.to_string() });
+            info!(%peer, "peer left");
+        }
+        self.update_metrics();
+        self.departed(&peer.to_string());
+    }
+
+    // Report the hosted session's clients, size and revision
+    pub fn update_metrics(&self) {
+        if let Session::Host(host) = &self.session {
+            self.metrics.set_clients(host.peer_count());
+            self.metrics.set_document(self.published_len, host.revision());
+        }
+    }
+
+    fn departed(&mut self, id: &str) {
+        if self.following.as_deref() == Some(id) {
+            let name = self.presence.get(id).map(|c| c.name.clone()).unwrap_or_default();
+            self.stop_following();
+            self.status_message = format!("{} left, no longer following", name);
+        }
+        self.presence.remove(id);
+    }
+
+    // Keep the viewport on a collaborator's cursor; an empty name picks the
+    // only other person in the session
+    fn follow(&mut self, name: &str) {
+        let id = if name.trim().is_empty() && self.presence.len() == 1 {
+            self.presence.iter().next().map(|(id, _)| id.as_str())
+        } else {
+            self.presence.find(name)
+        };
+        match id.map(str::to_string) {
+            Some(id) => {
+                self.following = Some(id);
+                self.status_message = "Press any navigation key to stop following".to_string();
+                self.scroll();
+            }
+            None => self.status_message = format!("Nobody called {} in the session", name.trim()),
+        }
+    }
+
+    fn stop_following(&mut self) {
+        if self.following.take().is_some() {
+            self.status_message.clear();
+        }
+    }
+
+    // Position of the followed collaborator's cursor, if they are still around
+    fn followed_position(&self) -> Option<(usize, usize)> {
+        let collaborator = self.presence.get(self.following.as_deref()?)?;
+        let text = self.text();
+        index_to_line_column(&text, collaborator.offset.min(text.len()))
+    }
+
+    pub fn peer_role(&self, peer: PeerId) -> Option<Role> {
+        match &self.session {
+            Session::Host(host) => host.role(peer),
+            Session::Guest(_) => None,
+        }
+    }
+
+    // The name the session's buffer goes by: its file's, without the
+    // directories, which are nobody else's business
+    fn buffer_name(&self) -> String {
+        let name = self.filename.as_deref().and_then(|filename| Path::new(filename).file_name());
+        name.map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    // Where the cursor of `id` is, in the session's buffer
+    fn presence_message(&self, id: &str, name: &str, offset: usize) -> WsMessage {
+        WsMessage::Presence { id: id.to_string(), name: name.to_string(), offset, buffer: self.buffer_name() }
+    }
+
+    // Everyone's cursor moved to the buffer the host opened or renamed
+    fn announce_buffer(&mut self) {
+        if !matches!(self.session, Session::Host(_)) {
+            return;
+        }
+        self.presence.rename_buffer(&self.buffer_name());
+        let Session::Host(host) = &self.session else {
+            return;
+        };
+        if !self.unattended {
+            host.broadcast(self.presence_message(HOST_ID, &self.name, self.cursor_offset()));
+        }
+        for (id, collaborator) in self.presence.iter() {
+            if let Ok(peer) = id.parse() {
+                host.broadcast_except(peer, self.presence_message(id, &collaborator.name, collaborator.offset));
+            }
+        }
+    }
+
+    // Tell a peer where everybody else is and what they commented
+    fn send_session_state(&self, peer: PeerId) {
+        let Session::Host(host) = &self.session else {
+            return;
+        };
+        let id = peer.to_string();
+        if !self.unattended {
+            host.send_to(peer, self.presence_message(HOST_ID, &self.name, self.cursor_offset()));
+        }
+        for (other, collaborator) in self.presence.iter().filter(|(other, _)| **other != id) {
+            host.send_to(peer, self.presence_message(other, &collaborator.name, collaborator.offset));
+        }
+        for (id, comment) in self.comments.iter() {
+            host.send_to(peer, comment_message(id, comment));
+        }
+    }
+
+    // Send a chat message to everyone in the session
+    fn say(&mut self, text: &str) {
+        match &self.session {
+            Session::Host(host) => {
+                host.broadcast(WsMessage::Chat { author: self.name.clone(), text: text.to_string() });
+                self.status_message = format!("{}: {}", self.name, text);
+            }
+            Session::Guest(guest) => {
+                // Shown once the host relays it back
+                if !guest.send_message(WsMessage::Chat { author: String::new(), text: text.to_string() }) {
+                    self.status_message = "Cannot chat while disconnected from the host".to_string();
+                }
+            }
+        }
+    }
+
+    pub fn peer_chat(&mut self, peer: PeerId, text: &str) {
+        let Session::Host(host) = &self.session else {
+            return;
+        };
+        let author = host.name(peer).unwrap_or_default().to_string();
+        let text = terminal::printable(text);
+        self.status_message = format!("{}: {}", author, text);
+        host.broadcast(WsMessage::Chat { author, text });
+    }
+
+    // Comment on the line the cursor is on
+    fn add_comment(&mut self, text: &str) {
+        let offset = self.cursor_offset() - self.cursor_x;
+        match &self.session {
+            Session::Host(_) => {
+                let comment = Comment { author: self.name.clone(), text: text.to_string(), offset, client: None };
+                self.publish_comment(comment);
+            }
+            Session::Guest(guest) => {
+                // Like cursors, offsets only mean the same to the host once
+                // it has all our changes
+                let sent = !guest.has_pending()
+                    && guest.send_message(WsMessage::AddComment { revision: guest.revision(), offset, text: text.to_string() });
+                if !sent {
+                    self.status_message = "Cannot comment until the host has received your changes".to_string();
+                }
+            }
+        }
+    }
+
+    // A peer commented on the line starting at `offset` in the document at `revision`
+    pub fn peer_comment(&mut self, peer: PeerId, revision: u64, offset: usize, text: &str) -> Result<(), OtError> {
+        let Session::Host(host) = &self.session else {
+            return Ok(());
+        };
+        let offset = host.transform_offset(revision, offset)?.min(self.published_len);
+        let author = host.name(peer).unwrap_or_default().to_string();
+        let client = host.client_id(peer).map(str::to_string);
+        self.publish_comment(Comment { author, text: terminal::printable(text), offset, client });
+        Ok(())
+    }
+
+    fn publish_comment(&mut self, comment: Comment) {
+        let message = comment_message(self.comments.add(comment.clone()), &comment);
+        if let Session::Host(host) = &self.session {
+            host.broadcast(message);
+        }
+        self.status_message = format!("{} commented: {}", comment.author, comment.text);
+    }
+
+    // Remove the comments on the cursor's line
+    fn resolve_comments(&mut self) {
+        let start = self.cursor_offset() - self.cursor_x;
+        let ids = self.comments.on_line(start, start + self.content[self.cursor_y].len());
+        if ids.is_empty() {
+            self.status_message = "No comments on this line".to_string();
+            return;
+        }
+        for id in ids {
+            match &self.session {
+                Session::Host(_) => self.resolve_comment(id),
+                Session::Guest(guest) => {
+                    guest.send_message(WsMessage::ResolveComment { id });
+                }
+            }
+        }
+    }
+
+    // Editors may resolve any comment, anyone else only their own
+    pub fn may_resolve_comment(&self, peer: PeerId, id: u64) -> bool {
+        let Session::Host(host) = &self.session else {
+            return false;
+        };
+        let own = match (self.comments.get(id).and_then(|c| c.client.as_deref()), host.client_id(peer)) {
+            (Some(author), Some(client)) => author == client,
+            _ => false,
+        };
+        own || self.peer_role(peer) == Some(Role::Editor)
+    }
+
+    // Drop a comment, and as the host tell everyone
+    pub fn resolve_comment(&mut self, id: u64) {
+        if self.comments.remove(id).is_none() {
+            return;
+        }
+        if let Session::Host(host) = &self.session {
+            host.broadcast(WsMessage::ResolveComment { id });
+        }
+    }
+
+    // Comments by the line they are on
+    pub fn comments_by_line(&self) -> BTreeMap<usize, Vec<&Comment>> {
+        let mut lines: BTreeMap<usize, Vec<&Comment>> = BTreeMap::new();
+        if self.comments.is_empty() {
+            return lines;
+        }
+        let text = self.text();
+        for (_, comment) in self.comments.iter() {
+            if let Some((line, _)) = index_to_line_column(&text, comment.offset.min(text.len())) {
+                lines.entry(line).or_default().push(comment);
+            }
+        }
+        lines
+    }
+
+    // A peer moved its cursor to `offset` in the document at `revision`
+    pub fn peer_cursor(&mut self, peer: PeerId, revision: u64, offset: usize) -> Result<(), OtError> {
+        let Session::Host(host) = &self.session else {
+            return Ok(());
+        };
+        let offset = host.transform_offset(revision, offset)?.min(self.published_len);
+        let id = peer.to_string();
+        let name = host.name(peer).unwrap_or_default().to_string();
+        host.broadcast_except(peer, self.presence_message(&id, &name, offset));
+        self.presence.update(&id, &name, &self.buffer_name(), offset);
+        self.scroll();
+        Ok(())
+    }
+
+    // Let the others know where our cursor is, if it moved. Offsets only
+    // make sense to them once the edit that moved it has been published too.
+    fn publish_cursor(&mut self) {
+        let offset = self.cursor_offset();
+        if self.unattended || self.published_cursor == Some(offset) || self.text().len() != self.published_len {
+            return;
+        }
+        let sent = match &self.session {
+            Session::Host(host) => {
+                host.broadcast(self.presence_message(HOST_ID, &self.name, offset));
+                true
+            }
+            Session::Guest(guest) => guest.send_cursor(offset),
+        };
+        if sent {
+            self.published_cursor = Some(offset);
+        }
+    }
+
+    pub fn start_op_log(&mut self, path: &Path) -> io::Result<()> {
+        let text = self.text();
+        match &mut self.session {
+            Session::Host(host) => host.start_log(path, &text),
+            Session::Guest(_) => Ok(()),
+        }
+    }
+
+    // Send a peer the whole document again, dropping whatever it has in flight
+    pub fn resync_peer(&mut self, peer: PeerId) {
+        let Session::Host(host) = &mut self.session else {
+            return;
+        };
+        if host.resync(peer, || buffer::text(&self.content)) == Resync::Sent {
+            self.send_session_state(peer);
+        }
+    }
+
+    // Apply a change a peer made against `revision` of the hosted document
+    #[tracing::instrument(level = "debug", skip(self, changes), fields(changes = changes.len()), err(Display))]
+    pub fn apply_remote_change(&mut self, peer: PeerId, revision: u64, changes: &[TextChange]) -> Result<(), OtError> {
+        let Session::Host(host) = &self.session else {
+            return Ok(());
+        };
+        let base_len = host.len_at(revision, self.published_len)?;
+        let op = host.transform_incoming(revision, Operation::from_changes(base_len, changes)?)?;
+        let max = self.limits.max_document_size;
+        if op.target_len() > max && op.target_len() > op.base_len() {
+            return Err(OtError::TooLarge { len: op.target_len(), max });
+        }
+
+        self.apply_operation(&op)?;
+        if let Session::Host(host) = &mut self.session {
+            host.commit(op, Some(peer));
+            self.metrics.op_committed(Instant::now());
+        }
+        self.update_metrics();
+        Ok(())
+    }
+
+    // Handle a message from the host of a session this editor joined
+    pub fn handle_host_message(&mut self, message: WsMessage) -> Result<(), OtError> {
+        let Session::Guest(guest) = &mut self.session else {
+            return Ok(());
+        };
+
+        match message {
+            WsMessage::Snapshot { text, revision } => {
+                if guest.reset(revision) {
+                    self.status_message = "Reloaded from the host, edits it had not received were lost".to_string();
+                }
+                let cursor = self.cursor_offset();
+                self.set_text(&text);
+                self.set_cursor_offset(cursor);
+                // The host follows up with everyone's cursor and comments
+                self.presence.clear();
+                self.comments.clear();
+                self.published_cursor = None;
+            }
+            WsMessage::Ack { revision } => guest.ack(revision),
+            WsMessage::Resumed { revision } => {
+                guest.resumed(revision);
+                // Where we were may not have reached the host before the
+                // connection dropped
+                self.published_cursor = None;
+            }
+            WsMessage::SessionEnd { reason } => {
+                // Keep editing on our own
+                self.session = Session::Host(HostSession::default());
+                self.status_message = format!("Session ended: {}", reason);
+            }
+            WsMessage::Change { revision, changes } => {
+                let op = Operation::from_changes(guest.host_len(self.published_len), &changes)?;
+                let op = guest.remote(revision, op)?;
+                self.apply_operation(&op)?;
+            }
+            WsMessage::SaveResult { revision, error: None } => {
+                // Clean only if we have nothing the host did not write
+                self.modified = guest.revision() != revision || guest.has_pending();
+                self.status_message = format!("Saved by host at revision {}", revision);
+            }
+            WsMessage::SaveResult { error: Some(error), .. } => {
+                self.status_message = format!("Host failed to save: {}", error);
+            }
+            WsMessage::Joined { role, .. } => {
+                self.read_only = role == Role::Viewer;
+                if role == Role::Viewer {
+                    self.status_message = "Joined as a viewer, the document is read-only".to_string();
+                }
+            }
+            WsMessage::Error { message } => self.status_message = format!("Host: {}", terminal::printable(&message)),
+            WsMessage::Presence { id, name, offset, buffer } => {
+                let offset = guest.transform_offset(offset).min(self.published_len);
+                self.presence.update(&id, &terminal::printable(&name), &terminal::printable(&buffer), offset);
+                self.scroll();
+            }
+            WsMessage::Departed { id } => self.departed(&id),
+            // Whatever the host relays is shown, so it is checked here too
+            WsMessage::Chat { author, text } => {
+                self.status_message = format!("{}: {}", terminal::printable(&author), terminal::printable(&text));
+            }
+            WsMessage::Comment { id, author, offset, text } => {
+                let (author, text) = (terminal::printable(&author), terminal::printable(&text));
+                let offset = guest.transform_offset(offset).min(self.published_len);
+                self.status_message = format!("{} commented: {}", author, text);
+                self.comments.insert(id, Comment { author, text, offset, client: None });
+            }
+            WsMessage::ResolveComment { id } => {
+                self.comments.remove(id);
+            }
+            WsMessage::Save | WsMessage::Cursor { .. } | WsMessage::AddComment { .. } => {}
+        }
+        self.publish_cursor();
+        Ok(())
+    }
+
+    // Save on behalf of a peer. The outcome reaches every peer from
+    // `write_file`, unless the host cannot even try.
+    pub fn remote_save(&mut self, peer: PeerId) {
+        let refused = if self.read_only {
+            Some("the host's buffer is read-only")
+        } else if self.filename.is_none() {
+            Some("the host's buffer has no file name yet")
+        } else {
+            None
+        };
+        let Session::Host(host) = &self.session else {
+            return;
+        };
+        if let Some(reason) = refused {
+            host.send_to(peer, WsMessage::SaveResult { revision: host.revision(), error: Some(reason.to_string()) });
+            return;
+        }
+        // Saving would not write anything, tell the peer why instead of
+        // leaving it waiting
+        if self.external_change.is_some() || self.check_disk() {
+            let reason = if self.external_change.is_some() {
+                "the file changed on disk, waiting for the host"
+            } else {
+                "the file changed on disk, save again to overwrite it"
+            };
+            if let Session::Host(host) = &self.session {
+                host.send_to(peer, WsMessage::SaveResult { revision: host.revision(), error: Some(reason.to_string()) });
+            }
+            self.status_message = format!("Peer {} asked to save: {}", peer, self.status_message);
+            return;
+        }
+        let _ = self.save_file();
+    }
+
+    pub fn refresh_screen(&self) -> io::Result<()> {
+        let mut frame = String::from(terminal::CLEAR_SCREEN);
+        let comments = self.comments_by_line();
+        // Draw content
+        for row in 0..self.terminal_rows {
+            let file_row = row + self.offset_y;
+            if file_row < self.content.len() {
+                let line = &self.content[file_row];
+                let start = buffer::floor_char_boundary(line, self.offset_x);
+                let end = buffer::floor_char_boundary(line, self.offset_x + self.terminal_cols);
+                if start < end {
+                    frame.push_str(&line[start..end]);
+                }
+                // Comments go dimmed after the end of their line, as far as they fit
+                if let Some(comments) = comments.get(&file_row) {
+                    let notes: Vec<String> = comments.iter().map(|c| format!("{}: {}", c.author, c.text)).collect();
+                    let room = (self.terminal_cols + self.offset_x).saturating_sub(line.len().max(self.offset_x) + 4);
+                    let note: String = notes.join(" | ").chars().take(room).collect();
+                    if !note.is_empty() {
+                        frame.push_str(&format!("\x1b[2m  # {}\x1b[m", note));
+                    }
+                }
+            } else {
+                frame.push('~');
+            }
+            frame.push_str("\r\n");
+        }
+
+        // Everyone else's cursor, in reverse video
+        if !self.presence.is_empty() {
+            let text = self.text();
+            for (_, collaborator) in self.presence.iter() {
+                let Some((line, col)) = index_to_line_column(&text, collaborator.offset.min(text.len())) else {
+                    continue;
+                };
+                let visible_rows = self.offset_y..self.offset_y + self.terminal_rows;
+                let visible_cols = self.offset_x..self.offset_x + self.terminal_cols;
+                if !visible_rows.contains(&line) || !visible_cols.contains(&col) {
+                    continue;
+                }
+                let under = self.content[line][col..].chars().next().unwrap_or(' ');
+                frame.push_str(&terminal::cursor_position(line - self.offset_y, col - self.offset_x));
+                frame.push_str(&format!("\x1b[7m{}\x1b[m", under));
+            }
+            frame.push_str(&terminal::cursor_position(self.terminal_rows, 0));
+        }
+
+        // Draw status bar
+        let status = if let Some(ref filename) = self.filename {
+            format!("{} - {} lines{} | {} | {}", 
+                filename, 
+                self.content.len(),
+                if self.modified { " (modified)" } else { "" },
+                self.format.encoding,
+                self.format.line_ending)
+        } else {
+            format!("[No Name] - {} lines{} | {} | {}", 
+                self.content.len(),
+                if self.modified { " (modified)" } else { "" },
+                self.format.encoding,
+                self.format.line_ending)
+        };
+        
+        let mut status = match self.presence.len() {
+            0 => status,
+            n => format!("{} | {} other{}", status, n, if n == 1 { "" } else { "s" }),
+        };
+        if let Some(followed) = self.following.as_deref().and_then(|id| self.presence.get(id)) {
+            status.push_str(&format!(" | following {}", followed.name));
+            if !followed.buffer.is_empty() {
+                status.push_str(&format!(" in {}", followed.buffer));
+            }
+        }
+        // Lines longer than the terminal would wrap and scroll the screen
+        let status: String = status.chars().take(self.terminal_cols).collect();
+        frame.push_str(&format!("\x1b[7m{:<width$}\x1b[m\r\n", status, width = self.terminal_cols));
+        if let Some(prompt) = &self.prompt {
+            let (line, col) = prompt.render_within(self.terminal_cols);
+            frame.push_str(&line);
+            frame.push_str(&terminal::cursor_position(self.terminal_rows + 1, col));
+            return self.terminal.draw(&frame);
+        }
+        frame.extend(self.status_message.chars().take(self.terminal_cols));
+
+        // Position cursor, on the followed collaborator's while following
+        let (line, col) = self.followed_position().unwrap_or((self.cursor_y, self.cursor_x));
+        frame.push_str(&terminal::cursor_position(
+            line.saturating_sub(self.offset_y),
+            col.saturating_sub(self.offset_x)
+        ));
+        self.terminal.draw(&frame)
+    }
+
+    pub fn process_keypress(&mut self, key: &Key) -> io::Result<bool> {
+        let changed = self.handle_keypress(key)?;
+        self.publish_cursor();
+        Ok(changed)
+    }
+
+    fn handle_keypress(&mut self, key: &Key) -> io::Result<bool> {
+        if self.prompt.is_some() {
+            self.prompt_keypress(key)?;
+            return Ok(false);
+        }
+
+        if self.read_only && matches!(key, Key::Enter | Key::Backspace | Key::Delete | Key::Char(_) | Key::Ctrl(b's' | b'w' | b'o')) {
+            self.status_message = "Buffer is read-only".to_string();
+            return Ok(false);
+        }
+
+        if matches!(key, Key::Up | Key::Down | Key::Left | Key::Right | Key::Home | Key::End) {
+            self.stop_following();
+        }
+
+        let changed: io::Result<bool> = match key {
+            Key::Ctrl(b'q') => {
+                self.request_quit();
+                return Ok(false);
+            }
+            Key::Ctrl(b's') => {
+                self.save_file()?;
+                return Ok(false);
+            }
+            Key::Ctrl(b'w') => {
+                self.open_prompt(PromptKind::SaveAs);
+                return Ok(false);
+            }
+            Key::Ctrl(b'o') => {
+                if self.modified {
+                    self.status_message = "File has unsaved changes! Save it before opening another.".to_string();
+                } else {
+                    self.open_prompt(PromptKind::Open);
+                }
+                return Ok(false);
+            }
+            Key::Ctrl(b'g') => {
+                self.open_prompt(PromptKind::GotoLine);
+                return Ok(false);
+            }
+            Key::Ctrl(b'f') => {
+                self.open_prompt(PromptKind::Search);
+                return Ok(false);
+            }
+            Key::Ctrl(b'n') => {
+                match self.prompt_history.last(PromptKind::Search).map(str::to_string) {
+                    Some(query) => self.search(&query),
+                    None => self.status_message = "Nothing to search for, press Ctrl+F first".to_string(),
+                }
+                return Ok(false);
+            }
+            Key::Ctrl(b't') => {
+                if self.presence.is_empty() {
+                    self.status_message = "Nobody else is in the session".to_string();
+                } else {
+                    self.prompt = Some(Prompt::new(PromptKind::Follow, ""));
+                }
+                return Ok(false);
+            }
+            Key::Ctrl(b'e') => {
+                self.open_prompt(PromptKind::Chat);
+                return Ok(false);
+            }
+            Key::Ctrl(b'r') => {
+                let label = format!("Comment on line {}: ", self.cursor_y + 1);
+                self.prompt = Some(Prompt::with_label(PromptKind::Comment, label, ""));
+                return Ok(false);
+            }
+            Key::Ctrl(b'k') => {
+                self.resolve_comments();
+                return Ok(false);
+            }
+            Key::Ctrl(b'h') => {
+                self.status_message = "Ctrl+Q: Quit | Ctrl+S: Save | Ctrl+W: Save as | Ctrl+O: Open | Ctrl+G: Go to line | Ctrl+F/N: Search/next | Ctrl+T: Follow | Ctrl+E: Chat | Ctrl+R/K: Comment/resolve".to_string();
+                return Ok(false);
+            }
+            Key::Up => self.move_cursor_up(),
+            Key::Down => self.move_cursor_down(),
+            Key::Left => self.move_cursor_left(),
+            Key::Right => self.move_cursor_right(),
+            Key::Home => {
+                self.cursor_x = 0;
+                Ok(false)
+            },
+            Key::End => {
+                if self.cursor_y < self.content.len() {
+                    self.cursor_x = self.content[self.cursor_y].len();
+                }
+                Ok(false)
+            }
+            Key::Enter => self.insert_newline(),
+            Key::Backspace => self.delete_char(),
+            Key::Delete => self.delete_char_forward(),
+            Key::Char(c) => self.insert_char(*c),
+            _ => {
+                // Debug: show what key was pressed
+                if let Key::Ctrl(code) = key {
+                    self.status_message = if code.is_ascii_lowercase() {
+                        format!("Pressed Ctrl+{} (code: {})", code.to_ascii_uppercase() as char, code - b'a' + 1)
+                    } else {
+                        format!("Pressed unknown key (code: {})", code)
+                    };
+                }
+                return Ok(false);
+            }
+        };
+
+        self.scroll();
+        changed
+    }
+
+    pub fn move_cursor_up(&mut self) -> io::Result<bool> {
+        if self.cursor_y > 0 {
+            self.cursor_y -= 1;
+            self.cursor_x = buffer::floor_char_boundary(&self.content[self.cursor_y], self.cursor_x);
+        }
+        Ok(false)
+    }
+
+    pub fn move_cursor_down(&mut self) -> io::Result<bool> {
+        if self.cursor_y < self.content.len() - 1 {
+            self.cursor_y += 1;
+            self.cursor_x = buffer::floor_char_boundary(&self.content[self.cursor_y], self.cursor_x);
+        }
+        Ok(false)
+    }
+
+    pub fn move_cursor_left(&mut self) -> io::Result<bool> {
+        if self.cursor_x > 0 {
+            self.cursor_x = buffer::previous_char_boundary(&self.content[self.cursor_y], self.cursor_x);
+        } else if self.cursor_y > 0 {
+            self.cursor_y -= 1;
+            self.cursor_x = self.content[self.cursor_y].len();
+        }
+        Ok(false)
+    }
+
+    pub fn move_cursor_right(&mut self) -> io::Result<bool> {
+        if self.cursor_y < self.content.len() {
+            let line = &self.content[self.cursor_y];
+            if let Some(c) = line[self.cursor_x..].chars().next() {
+                self.cursor_x += c.len_utf8();
+            } else if self.cursor_y < self.content.len() - 1 {
+                self.cursor_y += 1;
+                self.cursor_x = 0;
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn insert_char(&mut self, c: char) -> io::Result<bool> {
+        if self.cursor_y == self.content.len() {
+            self.content.push(String::new());
+        }
+        
+        self.content[self.cursor_y].insert(self.cursor_x, c);
+        self.cursor_x += c.len_utf8();
+        self.modified = true;
+        self.status_message.clear();
+
+        Ok(true)
+    }
+
+    pub fn insert_newline(&mut self) -> io::Result<bool> {
+        if self.cursor_y == self.content.len() {
+            self.content.push(String::new());
+        }
+        
+        let current_line = &self.content[self.cursor_y];
+        let new_line = current_line[self.cursor_x..].to_string();
+        self.content[self.cursor_y] = current_line[..self.cursor_x].to_string();
+        
+        self.cursor_y += 1;
+        self.cursor_x = 0;
+        self.content.insert(self.cursor_y, new_line);
+        self.modified = true;
+        self.status_message.clear();
+        
+        Ok(true)
+    }
+
+    pub fn delete_char(&mut self) -> io::Result<bool> {
+        if self.cursor_x == 0 && self.cursor_y == 0 {
+            return Ok(false);
+        }
+
+        if self.cursor_x > 0 {
+            self.cursor_x = buffer::previous_char_boundary(&self.content[self.cursor_y], self.cursor_x);
+            self.content[self.cursor_y].remove(self.cursor_x);
+        } else {
+            let current_line = self.content.remove(self.cursor_y);
+            self.cursor_y -= 1;
+            self.cursor_x = self.content[self.cursor_y].len();
+            self.content[self.cursor_y].push_str(&current_line);
+        }
+        
+        self.modified = true;
+        self.status_message.clear();
+
+        Ok(true)
+    }
+
+    pub fn delete_char_forward(&mut self) -> io::Result<bool> {
+        if self.cursor_y >= self.content.len() {
+            return Ok(false);
+        }
+
+        let line_len = self.content[self.cursor_y].len();
+        if self.cursor_x < line_len {
+            self.content[self.cursor_y].remove(self.cursor_x);
+        } else if self.cursor_y < self.content.len() - 1 {
+            let next_line = self.content.remove(self.cursor_y + 1);
+            self.content[self.cursor_y].push_str(&next_line);
+        } else {
+            return Ok(false);
+        }
+        
+        self.modified = true;
+        self.status_message.clear();
+
+        Ok(true)
+    }
+
+    fn scroll(&mut self) {
+        if let Some((line, col)) = self.followed_position() {
+            self.scroll_to(line, col);
+            return;
+        }
+        self.scroll_to(self.cursor_y, self.cursor_x);
+    }
+
+    // Move the viewport just enough to show `line` and `col`
+    fn scroll_to(&mut self, line: usize, col: usize) {
+        if line < self.offset_y {
+            self.offset_y = line;
+        }
+        if line >= self.offset_y + self.terminal_rows {
+            self.offset_y = line - self.terminal_rows + 1;
+        }
+        if col < self.offset_x {
+            self.offset_x = col;
+        }
+        if col >= self.offset_x + self.terminal_cols {
+            self.offset_x = col - self.terminal_cols + 1;
+        }
+    }
+
+    pub fn save_file(&mut self) -> io::Result<()> {
+        // The host owns the file
+        if let Session::Guest(guest) = &self.session {
+            self.status_message = if guest.request_save() {
+                "Asked the host to save".to_string()
+            } else {
+                "Cannot save while disconnected from the host".to_string()
+            };
+            return Ok(());
+        }
+
+        let filename = if let Some(ref fname) = self.filename {
+            fname.clone()
+        } else {
+            self.open_prompt(PromptKind::SaveAs);
+            return Ok(());
+        };
+
+        // Never overwrite changes made on disk without asking
+        if self.external_change.is_some() || self.check_disk() {
+            return Ok(());
+        }
+
+        self.write_file(&filename);
+        Ok(())
+    }
+
+    // Write the buffer to `filename`, reporting the outcome in the status line
+    fn write_file(&mut self, filename: &str) -> bool {
+        let content = file::encode(&self.content, self.format);
+        let saved = file::to_bytes(&content, self.format.encoding)
+            .and_then(|bytes| file::save_atomic(Path::new(filename), &bytes, self.backup));
+        if let Session::Host(host) = &self.session {
+            let error = saved.as_ref().err().map(|e| e.to_string());
+            host.broadcast(WsMessage::SaveResult { revision: host.revision(), error });
+        }
+        match saved {
+            Ok(_) => {
+                info!(file = filename, "saved");
+                self.modified = false;
+                self.status_message = format!("Saved to {}", filename);
+                self.disk = DiskState {
+                    stamp: file::stamp(Path::new(filename)).ok().flatten(),
+                    text: self.text(),
+                };
+                self.remove_swap();
+                true
+            }
+            Err(e) => {
+                warn!(file = filename, error = %e, "failed to save");
+                self.status_message = format!("Error saving: {}", e);
+                false
+            }
+        }
+    }
+
+    fn open_prompt(&mut self, kind: PromptKind) {
+        let initial = match kind {
+            PromptKind::SaveAs => self.filename.clone().unwrap_or_default(),
+            _ => String::new(),
+        };
+        self.prompt = Some(Prompt::new(kind, &initial));
+    }
+
+    // Quit right away when nothing would be lost, otherwise ask first
+    fn request_quit(&mut self) {
+        let mut reasons = Vec::new();
+        if self.modified {
+            reasons.push("unsaved changes".to_string());
+        }
+        match &self.session {
+            Session::Guest(guest) if guest.has_pending() => reasons.push("edits the host has not received yet".to_string()),
+            Session::Host(host) if host.peer_count() > 0 && !reasons.is_empty() => {
+                reasons.push(format!("{} connected client(s) will be disconnected", host.peer_count()))
+            }
+            _ => {}
+        }
+
+        if reasons.is_empty() {
+            self.quit();
+            return;
+        }
+        let mut label = reasons.join(", ");
+        label[..1].make_ascii_uppercase();
+        label.push_str(": (s)ave and quit, (d)iscard and quit or (c)ancel? ");
+        self.quit_state = QuitState::Confirming;
+        self.prompt = Some(Prompt::with_label(PromptKind::Quit, label, ""));
+    }
+
+    fn resolve_quit(&mut self, answer: &str) {
+        match answer.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('s') => {
+                self.quit_state = QuitState::SavingThenQuit;
+                let _ = self.save_file();
+            }
+            Some('d') => self.quit(),
+            Some('c') => {
+                self.quit_state = QuitState::Editing;
+                self.status_message = "Quit cancelled".to_string();
+            }
+            _ => self.request_quit(),
+        }
+    }
+
+    // Leave the editor: tell connected clients the session is over and stop
+    // everything running on the editor's behalf
+    pub fn quit(&mut self) {
+        if let Session::Host(host) = &mut self.session {
+            host.end("the host closed the document");
+        }
+        self.quit_state = QuitState::Quitting;
+        self.shutdown.token.cancel();
+    }
+
+    fn prompt_keypress(&mut self, key: &Key) -> io::Result<()> {
+        self.handle_prompt_key(key)?;
+
+        // Saving before quitting may have needed another prompt (a name, or
+        // what to do about changes on disk); once it is answered, quit only
+        // if the save went through
+        if self.quit_state == QuitState::SavingThenQuit && self.prompt.is_none() {
+            if self.modified {
+                self.quit_state = QuitState::Editing;
+            } else {
+                self.quit();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_prompt_key(&mut self, key: &Key) -> io::Result<()> {
+        let Some(prompt) = &mut self.prompt else {
+            return Ok(());
+        };
+        let kind = prompt.kind;
+        match prompt.handle_key(key, self.prompt_history.get(kind)) {
+            PromptEvent::Pending => {}
+            PromptEvent::Cancel if kind == PromptKind::ExternalChange => {
+                self.prompt = None;
+                self.resolve_external_change("keep");
+            }
+            PromptEvent::Cancel if kind == PromptKind::Quit => {
+                self.prompt = None;
+                self.resolve_quit("cancel");
+            }
+            PromptEvent::Submit(input) if kind == PromptKind::Quit => {
+                self.prompt = None;
+                self.resolve_quit(&input);
+            }
+            PromptEvent::Cancel if kind == PromptKind::Recover => {
+                self.prompt = None;
+                self.status_message = "Recovery skipped, the swap file is replaced on the next edit".to_string();
+            }
+            PromptEvent::Cancel => {
+                self.prompt = None;
+                self.status_message.clear();
+            }
+            PromptEvent::Submit(input) if kind == PromptKind::ExternalChange => {
+                self.prompt = None;
+                self.resolve_external_change(&input);
+            }
+            PromptEvent::Submit(input) if kind == PromptKind::Recover => {
+                self.prompt = None;
+                self.resolve_recovery(&input);
+            }
+            PromptEvent::Submit(input) if kind == PromptKind::Follow => {
+                self.prompt = None;
+                self.follow(&input);
+            }
+            PromptEvent::Submit(input) => {
+                self.prompt = None;
+                // An empty search repeats the previous one
+                let input = match (kind, input.is_empty()) {
+                    (PromptKind::Search, true) => self.prompt_history.last(kind).unwrap_or_default().to_string(),
+                    _ => input,
+                };
+                if input.is_empty() {
+                    self.status_message.clear();
+                    return Ok(());
+                }
+                self.prompt_history.push(kind, &input);
+                match kind {
+                    PromptKind::SaveAs => {
+                        if self.write_file(&input) {
+                            self.filename = Some(input);
+                            self.announce_buffer();
+                        }
+                    }
+                    PromptKind::Open => self.open_file(&input),
+                    PromptKind::GotoLine => self.goto_line(&input),
+                    PromptKind::Search => self.search(&input),
+                    PromptKind::Chat => self.say(&input),
+                    PromptKind::Comment => self.add_comment(&input),
+                    PromptKind::ExternalChange | PromptKind::Recover | PromptKind::Quit | PromptKind::Follow => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replace the buffer with `filename`, which becomes the shared document
+    fn open_file(&mut self, filename: &str) {
+        if let Session::Guest(_) = self.session {
+            self.status_message = "Cannot open files while joined to another editor".to_string();
+            return;
+        }
+
+        let (content, format, message) = match file::read(Path::new(filename)) {
+            Ok((content, format)) => (content, format, format!("Opened {}", filename)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                (vec![String::new()], FileFormat::default(), format!("New file {}", filename))
+            }
+            Err(e) => {
+                self.status_message = format!("Cannot open {}: {}", filename, e);
+                return;
+            }
+        };
+
+        let replaced = self.published_len;
+        self.disk = DiskState {
+            stamp: file::stamp(Path::new(filename)).ok().flatten(),
+            text: content.join("\n"),
+        };
+        self.content = content;
+        self.format = format;
+        self.filename = Some(filename.to_string());
+        self.modified = false;
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        self.offset_x = 0;
+        self.offset_y = 0;
+        self.publish(&[TextChange { text: self.text(), start: 0, end: replaced }]);
+        self.announce_buffer();
+        self.status_message = message;
+    }
+
+    // Look for changes other programs made to the file. Returns true when
+    // something needs to be shown, usually the prompt asking what to do.
+    pub fn check_disk(&mut self) -> bool {
+        let Some(filename) = self.filename.clone() else {
+            return false;
+        };
+        if self.external_change.is_some() || self.prompt.is_some() {
+            return false;
+        }
+        let path = Path::new(&filename);
+        let stamp = match file::stamp(path) {
+            Ok(stamp) if stamp != self.disk.stamp => stamp,
+            _ => return false,
+        };
+        if stamp.is_none() {
+            self.disk.stamp = None;
+            self.status_message = format!("{} was deleted on disk", filename);
+            return true;
+        }
+
+        let (lines, format) = match file::read(path) {
+            Ok(file) => file,
+            Err(e) => {
+                // Possibly caught halfway through being written, look again next time
+                debug!("Failed to read {} after it changed: {}", filename, e);
+                return false;
+            }
+        };
+        let text = lines.join("\n");
+        if text == self.disk.text || lines == self.content {
+            // Only touched, or changed to exactly what we have
+            self.modified = self.modified && lines != self.content;
+            self.disk = DiskState { stamp, text };
+            return false;
+        }
+
+        debug!("{} changed on disk", filename);
+        self.external_change = Some(ExternalChange { text, format, stamp });
+        if self.unattended {
+            // Take what is on disk, without losing edits that are not saved yet
+            self.resolve_external_change(if self.modified { "m" } else { "r" });
+            info!("{} changed on disk: {}", filename, self.status_message);
+        } else {
+            self.prompt = Some(Prompt::new(PromptKind::ExternalChange, ""));
+        }
+        true
+    }
+
+    fn resolve_external_change(&mut self, answer: &str) {
+        let Some(change) = self.external_change.take() else {
+            return;
+        };
+
+        match answer.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('r') => {
+                self.replace_text(&change.text);
+                self.format = change.format;
+                self.modified = false;
+                self.status_message = "Reloaded from disk".to_string();
+            }
+            Some('k') => {
+                self.modified = true;
+                self.status_message = "Kept the buffer, saving will replace the file on disk".to_string();
+            }
+            Some('m') => {
+                let (merged, conflicts) = file::merge(&self.disk.text, &self.text(), &change.text);
+                self.replace_text(&merged);
+                self.modified = true;
+                if conflicts && self.unattended {
+                    // Nobody was asked, and every client now has the markers
+                    warn!("Merged changes from disk with conflicts, the document has <<<<<<< markers");
+                }
+                self.status_message = if conflicts {
+                    "Merged with conflicts, look for <<<<<<< markers".to_string()
+                } else {
+                    "Merged changes from disk".to_string()
+                };
+            }
+            _ => {
+                // Ask again
+                self.external_change = Some(change);
+                self.prompt = Some(Prompt::new(PromptKind::ExternalChange, ""));
+                return;
+            }
+        }
+        self.disk = DiskState { stamp: change.stamp, text: change.text };
+    }
+
+    // Change the whole document to `text`, sending peers only the part that differs
+    fn replace_text(&mut self, text: &str) {
+        let old = self.text();
+        let prefix = old.bytes().zip(text.bytes()).take_while(|(a, b)| a == b).count();
+        let suffix = old.as_bytes()[prefix..]
+            .iter()
+            .rev()
+            .zip(text.as_bytes()[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // Stay on character boundaries
+        let start = (0..=prefix).rev().find(|&i| old.is_char_boundary(i) && text.is_char_boundary(i)).unwrap_or(0);
+        let suffix = (0..=suffix).rev().find(|&n| old.is_char_boundary(old.len() - n) && text.is_char_boundary(text.len() - n)).unwrap_or(0);
+        let change = TextChange { text: text[start..text.len() - suffix].to_string(), start, end: old.len() - suffix };
+
+        let cursor = match Operation::from_changes(old.len(), std::slice::from_ref(&change)) {
+            Ok(op) => op.transform_index(self.cursor_offset()),
+            Err(_) => 0,
+        };
+        let published_len = self.published_len;
+        self.content = buffer::lines(text);
+        self.set_cursor_offset(cursor);
+        self.published_len = published_len;
+        self.publish(&[change]);
+    }
+
+    fn swap_path(&self) -> Option<PathBuf> {
+        let filename = self.filename.as_ref().filter(|_| self.swap)?;
+        Some(file::swap_path(Path::new(filename)))
+    }
+
+    // Ask about a swap file left behind by an earlier session. Returns true
+    // when there is one.
+    pub fn offer_recovery(&mut self) -> bool {
+        let Some(path) = self.swap_path() else {
+            return false;
+        };
+        let Ok(raw) = fs::read_to_string(&path) else {
+            return false;
+        };
+        if file::decode(&raw).0 == self.content {
+            // Nothing was lost
+            let _ = fs::remove_file(&path);
+            return false;
+        }
+        if self.unattended {
+            // Never throw away someone's work unasked
+            self.resolve_recovery("r");
+            info!("{}", self.status_message);
+        } else {
+            self.prompt = Some(Prompt::new(PromptKind::Recover, ""));
+        }
+        true
+    }
+
+    fn resolve_recovery(&mut self, answer: &str) {
+        let Some(path) = self.swap_path() else {
+            return;
+        };
+        match answer.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('r') => match fs::read_to_string(&path) {
+                Ok(raw) => {
+                    self.replace_text(&file::decode(&raw).0.join("\n"));
+                    self.modified = true;
+                    // Now ours, to be replaced on the next edit and removed on save
+                    self.swap_written = Some(path);
+                    self.status_message = "Recovered unsaved changes, save to keep them".to_string();
+                }
+                Err(e) => self.status_message = format!("Cannot read {}: {}", path.display(), e),
+            },
+            Some('d') => {
+                remove_file(&path);
+                self.remove_swap();
+                self.status_message = "Discarded the swap file".to_string();
+            }
+            _ => self.prompt = Some(Prompt::new(PromptKind::Recover, "")),
+        }
+    }
+
+    // Called periodically: refresh the swap file after edits, and autosave
+    // once editing has paused. Returns true when the screen needs redrawing.
+    pub fn autosave_tick(&mut self) -> bool {
+        if !self.modified || self.read_only {
+            return false;
+        }
+        if let Some(idle) = self.autosave {
+            if self.filename.is_some() && self.last_edit.elapsed() >= idle && self.prompt.is_none() {
+                if self.save_file().is_ok() && !self.modified {
+                    self.status_message = format!("Autosaved {}", self.filename.as_deref().unwrap_or_default());
+                }
+                // After a failure, try again only after another pause
+                self.last_edit = Instant::now();
+                return true;
+            }
+        }
+        self.write_swap();
+        false
+    }
+
+    // Bring the swap file up to date with the buffer
+    pub fn write_swap(&mut self) {
+        if !self.modified || self.read_only {
+            return;
+        }
+        if let (Some(path), true) = (self.swap_path(), self.edits != self.swapped_edits) {
+            // Always UTF-8, whatever the file's own encoding
+            let content = file::encode(&self.content, self.format);
+            let original = Path::new(self.filename.as_deref().unwrap_or_default());
+            match file::save_swap(&path, original, content.as_bytes()) {
+                Ok(()) => {
+                    self.swapped_edits = self.edits;
+                    self.swap_written = Some(path);
+                }
+                Err(e) => debug!("Failed to write swap file {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn remove_swap(&mut self) {
+        if let Some(path) = self.swap_written.take() {
+            remove_file(&path);
+        }
+        self.swapped_edits = self.edits;
+    }
+
+    fn goto_line(&mut self, input: &str) {
+        match input.trim().parse::<usize>() {
+            Ok(line) if line >= 1 => {
+                self.stop_following();
+                self.cursor_y = (line - 1).min(self.content.len() - 1);
+                self.cursor_x = 0;
+                self.scroll();
+                self.status_message.clear();
+            }
+            _ => self.status_message = format!("Not a line number: {}", input),
+        }
+    }
+
+    // Move to the next match after the cursor, wrapping around at the end
+    fn search(&mut self, query: &str) {
+        let text = self.text();
+        let from = self.cursor_offset() + 1;
+        let after = text.get(from..).and_then(|rest| rest.find(query)).map(|i| from + i);
+        let found = match after {
+            Some(offset) => Some((offset, false)),
+            None => text.find(query).map(|offset| (offset, true)),
+        };
+
+        match found {
+            Some((offset, wrapped)) => {
+                self.stop_following();
+                self.set_cursor_offset(offset);
+                self.status_message = if wrapped { format!("Search wrapped: {}", query) } else { String::new() };
+            }
+            None => self.status_message = format!("Not found: {}", query),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QuitState {
+    Editing,
+    // The quit prompt is open
+    Confirming,
+    // Asked to save first, quitting once that worked
+    SavingThenQuit,
+    Quitting,
+}
+
+// Stops the editor and everything running on its behalf
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    pub token: CancellationToken,
+    // Client connections, waited for so goodbyes reach peers before exiting
+    pub connections: TaskTracker,
+}
+
+impl Shutdown {
+    // Stop everything, giving connected clients up to `timeout` to hear that
+    // the session ended
+    pub async fn finish(&self, timeout: Duration) {
+        self.token.cancel();
+        self.connections.close();
+        let _ = tokio::time::timeout(timeout, self.connections.wait()).await;
+    }
+}
+
+// What is known about the file on disk
+struct DiskState {
+    stamp: Option<Stamp>,
+    text: String,
+}
+
+// A version of the file written by another program, waiting for the user to
+// decide what to do with it
+struct ExternalChange {
+    text: String,
+    format: FileFormat,
+    stamp: Option<Stamp>,
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        let _ = self.terminal.exit_raw_mode();
+    }
+}
+
+// Notice changes other programs make to the open file
+pub async fn watch_file(hub: Hub) {
+    tick_every(&hub, DISK_POLL_INTERVAL, Tick::Disk).await;
+}
+
+pub async fn autosave(hub: Hub) {
+    tick_every(&hub, SWAP_INTERVAL, Tick::Autosave).await;
+}
+
+async fn tick_every(hub: &Hub, period: Duration, tick: Tick) {
+    let shutdown = hub.shutdown().token.clone();
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        hub.tick(tick).await;
+    }
+}
+
+pub async fn run(hub: Hub) -> io::Result<()> {
+    let terminal = hub.with(|editor| editor.terminal.clone()).await;
+    terminal.enter_raw_mode()?;
+    terminal.draw(terminal::CLEAR_SCREEN)?;
+    hub.with(|editor| editor.refresh_screen()).await?;
+
+    // Reading the terminal blocks, so it gets a thread of its own
+    let shutdown = hub.shutdown().token.clone();
+    let keys = hub.clone();
+    let reader = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        while !shutdown.is_cancelled() {
+            if let Some(key) = terminal.read_key()? {
+                debug!("Key pressed: {:?}", key);
+                keys.press(key)?;
+            }
+        }
+        Ok(())
+    });
+    reader.await.map_err(io::Error::other)??;
+
+    // Quitting on purpose, possibly discarding changes: nothing to recover
+    let terminal = hub.with(|editor| {
+        editor.remove_swap();
+        editor.terminal.clone()
+    })
+    .await;
+    terminal.exit_raw_mode()?;
+    terminal.draw(terminal::CLEAR_SCREEN)
+}
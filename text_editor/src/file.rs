@@ -0,0 +1,291 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use diffy::{ConflictStyle, MergeOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        })
+    }
+}
+
+// How a file was laid out on disk, so saving writes it back byte for byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
+}
+
+// Read and decode a file, working out its encoding and line endings
+pub fn read(path: &Path) -> io::Result<(Vec<String>, FileFormat)> {
+    let (text, encoding) = from_bytes(&fs::read(path)?)?;
+    let (lines, format) = decode(&text);
+    Ok((lines, FileFormat { encoding, ..format }))
+}
+
+// UTF-16 is only recognised by its byte order mark. Anything that is not
+// valid UTF-8 otherwise is taken to be Latin-1, which every byte sequence is.
+pub fn from_bytes(bytes: &[u8]) -> io::Result<(String, Encoding)> {
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        let text = String::from_utf8(rest.to_vec()).map_err(|e| invalid_data(format!("invalid UTF-8: {}", e)))?;
+        return Ok((text, Encoding::Utf8Bom));
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        return Ok((from_utf16(rest, u16::from_le_bytes)?, Encoding::Utf16Le));
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        return Ok((from_utf16(rest, u16::from_be_bytes)?, Encoding::Utf16Be));
+    }
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(text) => Ok((text, Encoding::Utf8)),
+        Err(_) => Ok((bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1)),
+    }
+}
+
+fn from_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> io::Result<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(invalid_data("truncated UTF-16".to_string()));
+    }
+    let units = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map_err(|e| invalid_data(format!("invalid UTF-16: {}", e)))
+}
+
+pub fn to_bytes(text: &str, encoding: Encoding) -> io::Result<Vec<u8>> {
+    Ok(match encoding {
+        Encoding::Utf8 => text.as_bytes().to_vec(),
+        Encoding::Utf8Bom => [b"\xEF\xBB\xBF".as_slice(), text.as_bytes()].concat(),
+        Encoding::Utf16Le => [0xFF, 0xFE].into_iter().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect(),
+        Encoding::Utf16Be => [0xFE, 0xFF].into_iter().chain(text.encode_utf16().flat_map(u16::to_be_bytes)).collect(),
+        Encoding::Latin1 => text
+            .chars()
+            .map(|c| u8::try_from(c).map_err(|_| invalid_data(format!("{:?} cannot be saved as Latin-1", c))))
+            .collect::<io::Result<_>>()?,
+    })
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Split file contents into lines. CRLF is only treated as the line ending
+// when every line uses it; otherwise any `\r` stays part of its line, which
+// keeps files with mixed endings intact.
+pub fn decode(raw: &str) -> (Vec<String>, FileFormat) {
+    let breaks = raw.matches('\n').count();
+    let line_ending = if breaks > 0 && raw.matches("\r\n").count() == breaks {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+
+    let body = raw.strip_suffix(line_ending.as_str());
+    let final_newline = body.is_some();
+    let lines = body
+        .unwrap_or(raw)
+        .split(line_ending.as_str())
+        .map(|line| line.to_string())
+        .collect();
+    (lines, FileFormat { line_ending, final_newline, ..FileFormat::default() })
+}
+
+pub fn encode(lines: &[String], format: FileFormat) -> String {
+    let mut text = lines.join(format.line_ending.as_str());
+    if format.final_newline {
+        text.push_str(format.line_ending.as_str());
+    }
+    text
+}
+
+// Cheap fingerprint of a file, compared to notice changes made by others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+// None when the file does not exist
+pub fn stamp(path: &Path) -> io::Result<Option<Stamp>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(Stamp { modified: metadata.modified()?, len: metadata.len() })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Line-based three-way merge of two versions of `base`. Where both changed
+// the same lines, both versions are kept between conflict markers; the
+// second value tells whether that happened.
+pub fn merge(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+    // diffy expects newline terminated lines
+    let [base, ours, theirs] = [base, ours, theirs].map(|text| format!("{}\n", text));
+    let (mut merged, conflicts) = match MergeOptions::new().set_conflict_style(ConflictStyle::Merge).merge(&base, &ours, &theirs) {
+        Ok(merged) => (merged, false),
+        Err(merged) => (merged, true),
+    };
+    merged.pop();
+    (merged, conflicts)
+}
+
+// Replace the file at `path` with `data` without ever leaving a truncated
+// file behind: write a temporary file next to it, flush it to disk and rename
+// it over the original. The original's mode and ownership carry over, and with
+// `backup` it is kept as `path~`.
+pub fn save_atomic(path: &Path, data: &[u8], backup: bool) -> io::Result<()> {
+    // Saving through a symlink replaces the file it points to, not the link
+    let path = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(e),
+    };
+    let original = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    if backup && original.is_some() {
+        fs::copy(&path, backup_path(&path))?;
+    }
+
+    replace(&path, data, original.as_ref(), 0o666)
+}
+
+// Write the swap file of `original`. It takes the original's mode and
+// ownership, or is private while the original does not exist yet, so it
+// never shows more than the file itself does.
+pub fn save_swap(swap: &Path, original: &Path, data: &[u8]) -> io::Result<()> {
+    let original = match fs::metadata(original) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    replace(swap, data, original.as_ref(), 0o600)
+}
+
+// Write `data` next to `path`, then rename it over `path`. Without an
+// original to copy, the file is created with `mode` (less the umask).
+fn replace(path: &Path, data: &[u8], original: Option<&fs::Metadata>, mode: u32) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = write_temp(&temp, data, original, mode).and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+
+    sync_dir(path)
+}
+
+// Crash recovery copy of the unsaved buffer, `.name.te-swp` next to the
+// file; other editors' swap files are none of our business
+pub fn swap_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".te-swp");
+    path.with_file_name(name)
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push("~");
+    PathBuf::from(name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+fn write_temp(temp: &Path, data: &[u8], original: Option<&fs::Metadata>, mode: u32) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Private until it has the original's permissions
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if original.is_some() { 0o600 } else { mode });
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(temp)?;
+    file.write_all(data)?;
+
+    if let Some(original) = original {
+        file.set_permissions(original.permissions())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Only root may give files away; anyone else keeps their own ownership
+            match std::os::unix::fs::fchown(&file, Some(original.uid()), Some(original.gid())) {
+                Err(e) if e.kind() != io::ErrorKind::PermissionDenied => return Err(e),
+                _ => {}
+            }
+        }
+    }
+
+    file.sync_all()
+}
+
+// Make the rename itself durable
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
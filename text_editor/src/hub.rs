@@ -0,0 +1,165 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug_span, warn};
+
+use crate::editor::{Editor, Shutdown};
+use crate::limits::Limits;
+use crate::metrics::Metrics;
+use crate::protocol::ConnectParams;
+use crate::server;
+use crate::session::{Outbox, PeerId};
+use crate::terminal::Key;
+
+// Messages from all connections, keys and timers that may wait for the editor
+const COMMAND_QUEUE: usize = 256;
+// Commands handled before the screen is redrawn
+const COMMAND_BATCH: usize = 64;
+
+// What the editor is asked to do
+enum Command {
+    Connect { params: ConnectParams, outbox: Outbox, reply: oneshot::Sender<Option<PeerId>> },
+    Message { peer: PeerId, text: String },
+    Disconnect(PeerId),
+    // A key pressed at the terminal, answered once it has been handled
+    Key(Key, oneshot::Sender<io::Result<()>>),
+    Tick(Tick),
+    // Anything else: joining a session, starting up and shutting down
+    Run(Box<dyn FnOnce(&mut Editor) + Send>),
+}
+
+// Periodic work, redrawing only when it changed something
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    // Look for changes other programs made to the file
+    Disk,
+    // Refresh the swap file, and autosave once editing has paused
+    Autosave,
+}
+
+// The way to the editor. Only the thread started by `Hub::start` touches it,
+// handling keys, timers and what every connection sends in the order they
+// arrive, so nothing waits on a lock and the runtime never waits on the
+// disk or the terminal.
+#[derive(Clone)]
+pub struct Hub {
+    commands: mpsc::Sender<Command>,
+    limits: Limits,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+}
+
+impl Hub {
+    // Hand `editor` to a thread of its own, until every `Hub` is dropped
+    pub fn start(editor: Editor) -> Hub {
+        let (commands, rx) = mpsc::channel(COMMAND_QUEUE);
+        editor.update_metrics();
+        let hub = Hub { commands, limits: editor.limits, metrics: editor.metrics.clone(), shutdown: editor.shutdown.clone() };
+        tokio::task::spawn_blocking(move || apply_commands(editor, rx));
+        hub
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    // Run `f` on the editor and wait for what it returns
+    pub async fn with<T: Send + 'static>(&self, f: impl FnOnce(&mut Editor) -> T + Send + 'static) -> T {
+        let (reply, result) = oneshot::channel();
+        let run = Box::new(move |editor: &mut Editor| {
+            let _ = reply.send(f(editor));
+        });
+        if self.commands.send(Command::Run(run)).await.is_err() {
+            panic!("the editor thread has stopped");
+        }
+        result.await.expect("the editor thread has stopped")
+    }
+
+    // Handle a key and wait until it has been, from the thread reading the
+    // terminal: keys are not read ahead of quitting
+    pub fn press(&self, key: Key) -> io::Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.commands
+            .blocking_send(Command::Key(key, reply))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the editor thread has stopped"))?;
+        done.blocking_recv()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "the editor thread has stopped")))
+    }
+
+    pub async fn tick(&self, tick: Tick) {
+        let _ = self.commands.send(Command::Tick(tick)).await;
+    }
+
+    // Add a peer sending to `outbox`. None when it was turned away.
+    pub async fn connect(&self, params: ConnectParams, outbox: Outbox) -> Option<PeerId> {
+        let (reply, joined) = oneshot::channel();
+        self.commands.send(Command::Connect { params, outbox, reply }).await.ok()?;
+        joined.await.ok().flatten()
+    }
+
+    // Waits while the editor is busy. Returns false once it is gone.
+    pub async fn message(&self, peer: PeerId, text: String) -> bool {
+        self.commands.send(Command::Message { peer, text }).await.is_ok()
+    }
+
+    pub async fn disconnect(&self, peer: PeerId) {
+        let _ = self.commands.send(Command::Disconnect(peer)).await;
+    }
+}
+
+fn apply_commands(mut editor: Editor, mut commands: mpsc::Receiver<Command>) {
+    while let Some(command) = commands.blocking_recv() {
+        let mut redraw = apply(&mut editor, command);
+        // Whatever else arrived meanwhile goes into the same frame
+        for _ in 1..COMMAND_BATCH {
+            let Ok(command) = commands.try_recv() else {
+                break;
+            };
+            redraw |= apply(&mut editor, command);
+        }
+        if redraw {
+            let _ = editor.refresh_screen();
+        }
+    }
+}
+
+// Returns whether the screen needs redrawing
+fn apply(editor: &mut Editor, command: Command) -> bool {
+    match command {
+        Command::Connect { params, outbox, reply } => {
+            let _ = reply.send(editor.add_peer(outbox, params));
+        }
+        Command::Message { peer, text } => debug_span!("message", %peer).in_scope(|| server::handle_message(editor, peer, &text)),
+        Command::Disconnect(peer) => editor.remove_peer(peer),
+        // Drawn before the next key is read, like a typed key always was
+        Command::Key(key, reply) => {
+            let handled = handle_key(editor, &key).and_then(|()| editor.refresh_screen());
+            let _ = reply.send(handled);
+            return false;
+        }
+        Command::Tick(Tick::Disk) => return editor.check_disk(),
+        Command::Tick(Tick::Autosave) => return editor.autosave_tick(),
+        Command::Run(f) => f(editor),
+    }
+    true
+}
+
+// Peers are sent the change through their queues, nothing here waits
+fn handle_key(editor: &mut Editor, key: &Key) -> io::Result<()> {
+    if editor.process_keypress(key)? {
+        let start = editor.cursor_offset();
+        if let Err(e) = editor.broadcast_change(key, start) {
+            warn!(error = %e, "failed to broadcast change");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,26 @@
+// A terminal text editor whose documents can be edited by several people at
+// once. The binary wires these together; the collaboration server and the
+// buffer can also be embedded on their own.
+
+pub mod buffer;
+pub mod cli;
+pub mod client;
+pub mod comments;
+pub mod editor;
+pub mod file;
+pub mod hub;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod oplog;
+pub mod ot;
+pub mod presence;
+pub mod prompt;
+pub mod protocol;
+pub mod server;
+pub mod session;
+pub mod terminal;
+pub mod tls;
+
+pub use editor::Editor;
+pub use terminal::{Key, Terminal};
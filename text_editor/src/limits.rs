@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+// What a single client may send to the collaboration server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Bytes in one WebSocket message
+    pub max_message_size: usize,
+    // Bytes the document may grow to through remote changes
+    pub max_document_size: usize,
+    // Messages per second a client may keep sending; it may send a second's
+    // worth at once before it is slowed down
+    pub messages_per_second: u32,
+}
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MESSAGES_PER_SECOND: u32 = 50;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_document_size: DEFAULT_MAX_DOCUMENT_SIZE,
+            messages_per_second: DEFAULT_MESSAGES_PER_SECOND,
+        }
+    }
+}
+
+// Token bucket pacing the messages of one client. A client over its rate is
+// not cut off: the server stops reading from it for a while, so its socket
+// fills up and it has to slow down.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    // Messages the client may still send right away; negative when it owes
+    // time for messages already taken
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(messages_per_second: u32, now: Instant) -> Self {
+        let rate = f64::from(messages_per_second.max(1));
+        RateLimiter { rate, tokens: rate, last: now }
+    }
+
+    // Take one message, returning how long to wait before handling it
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+use tracing_appender::rolling::RollingFileAppender;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+use crate::cli::{LogLevel, LogRotation};
+
+// Rotated log files kept besides the one being written
+pub const MAX_LOG_FILES: usize = 7;
+
+// Where log lines go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    // A file, started anew every `rotation` period with the date appended
+    File { path: PathBuf, rotation: LogRotation },
+    // For running without a terminal UI, under a service manager collecting it
+    Stderr,
+}
+
+// `$XDG_STATE_HOME/text_editor`, falling back to `~/.local/state/text_editor`
+pub fn state_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(Path::new(&std::env::var_os("HOME")?).join(".local/state")))?;
+    Some(base.join("text_editor"))
+}
+
+// Where the editor logs when not told otherwise
+pub fn default_log_file() -> Option<PathBuf> {
+    Some(state_dir()?.join("text_editor.log"))
+}
+
+// Where `--log-file` and `--log-rotation` send the log: the file given,
+// stderr for `-`, or else the default log file started anew every day. None
+// when there is no state directory for the default one.
+pub fn target(file: Option<&Path>, rotation: Option<LogRotation>) -> io::Result<Option<LogTarget>> {
+    match file {
+        Some(path) if path.as_os_str() == "-" => match rotation {
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "--log-rotation needs a log file, not stderr")),
+            None => Ok(Some(LogTarget::Stderr)),
+        },
+        Some(path) => Ok(Some(LogTarget::File { path: path.to_path_buf(), rotation: rotation.unwrap_or(LogRotation::Never) })),
+        None => Ok(default_log_file().map(|path| LogTarget::File { path, rotation: rotation.unwrap_or(LogRotation::Daily) })),
+    }
+}
+
+// Open the log, creating its directory if needed
+pub fn writer(target: &LogTarget) -> io::Result<BoxMakeWriter> {
+    let (path, rotation) = match target {
+        LogTarget::Stderr => return Ok(BoxMakeWriter::new(io::stderr)),
+        LogTarget::File { path, rotation } => (path, *rotation),
+    };
+    let name = path.file_name().and_then(OsStr::to_str).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid log file name {}", path.display()))
+    })?;
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation.into())
+        .filename_prefix(name)
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir)
+        .map_err(|e| io::Error::other(format!("failed to open log file {}: {}", path.display(), e)))?;
+    Ok(BoxMakeWriter::new(appender))
+}
+
+// Send log lines and spans, including those of libraries using `log`, to
+// `target`. Only the first call in a process takes effect.
+pub fn init(target: &LogTarget, level: LogLevel) -> io::Result<()> {
+    if level == LogLevel::Off {
+        return Ok(());
+    }
+    let ansi = *target == LogTarget::Stderr && io::stderr().is_terminal();
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(level))
+        .with_writer(writer(target)?)
+        .with_ansi(ansi)
+        .try_init();
+    Ok(())
+}
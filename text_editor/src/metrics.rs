@@ -0,0 +1,147 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// Seconds over which ops per second are averaged
+pub const RATE_WINDOW: u64 = 10;
+
+// Counters about the collaboration server, shared with whoever reports them
+#[derive(Debug)]
+pub struct Metrics {
+    // Messages thrown away unread: too large or not understood
+    dropped_messages: AtomicU64,
+    // Changes refused: invalid, from a viewer, or over the size limit
+    rejected_changes: AtomicU64,
+    // What the session looks like after the last change or (dis)connection
+    connected_clients: AtomicU64,
+    document_size: AtomicU64,
+    revision: AtomicU64,
+    // Changes committed, by anyone
+    ops: AtomicU64,
+    // Time spent applying the changes of peers
+    apply_micros: AtomicU64,
+    applied_changes: AtomicU64,
+    // Ops committed in each of the last seconds, by second since `started`
+    recent_ops: Mutex<[(u64, u64); RATE_WINDOW as usize]>,
+    started: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            dropped_messages: AtomicU64::default(),
+            rejected_changes: AtomicU64::default(),
+            connected_clients: AtomicU64::default(),
+            document_size: AtomicU64::default(),
+            revision: AtomicU64::default(),
+            ops: AtomicU64::default(),
+            apply_micros: AtomicU64::default(),
+            applied_changes: AtomicU64::default(),
+            recent_ops: Mutex::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn message_dropped(&self) {
+        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn change_rejected(&self) {
+        self.rejected_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_changes(&self) -> u64 {
+        self.rejected_changes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_clients(&self, clients: usize) {
+        self.connected_clients.store(clients as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_document(&self, size: usize, revision: u64) {
+        self.document_size.store(size as u64, Ordering::Relaxed);
+        self.revision.store(revision, Ordering::Relaxed);
+    }
+
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn document_size(&self) -> u64 {
+        self.document_size.load(Ordering::Relaxed)
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Relaxed)
+    }
+
+    pub fn op_committed(&self, now: Instant) {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        let second = self.second(now);
+        let mut recent = self.recent_ops.lock();
+        let slot = &mut recent[(second % RATE_WINDOW) as usize];
+        if slot.0 != second {
+            *slot = (second, 0);
+        }
+        slot.1 += 1;
+    }
+
+    pub fn change_applied(&self, took: Duration) {
+        self.apply_micros.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        self.applied_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ops(&self) -> u64 {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    // Ops committed per second over the last `RATE_WINDOW` seconds
+    pub fn ops_per_second(&self, now: Instant) -> f64 {
+        let second = self.second(now);
+        let recent = self.recent_ops.lock();
+        let ops: u64 = recent.iter().filter(|(s, _)| *s + RATE_WINDOW > second && *s <= second).map(|(_, n)| n).sum();
+        ops as f64 / RATE_WINDOW as f64
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    // Everything above in the Prometheus text format
+    pub fn prometheus(&self, now: Instant) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+        };
+        metric("text_editor_connected_clients", "gauge", "Clients connected to the session.", &self.connected_clients());
+        metric("text_editor_document_bytes", "gauge", "Size of the shared document in bytes.", &self.document_size());
+        metric("text_editor_revision", "gauge", "Revision of the shared document.", &self.revision());
+        metric("text_editor_ops_total", "counter", "Changes committed to the document.", &self.ops());
+        metric(
+            "text_editor_ops_per_second",
+            "gauge",
+            "Changes committed per second, averaged over the last 10 seconds.",
+            &self.ops_per_second(now),
+        );
+        metric("text_editor_dropped_messages_total", "counter", "Messages dropped unread.", &self.dropped_messages());
+        metric("text_editor_rejected_changes_total", "counter", "Changes rejected.", &self.rejected_changes());
+        let _ = write!(
+            out,
+            "# HELP text_editor_apply_latency_seconds Time taken to apply a change from a client.\n\
+             # TYPE text_editor_apply_latency_seconds summary\n\
+             text_editor_apply_latency_seconds_sum {}\n\
+             text_editor_apply_latency_seconds_count {}\n",
+            self.apply_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            self.applied_changes.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
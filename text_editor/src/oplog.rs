@@ -0,0 +1,109 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ot::{Operation, OtError};
+use crate::protocol::TextChange;
+
+// One line of the op log: the change that produced `revision`, relative to
+// the document at the previous revision. Every time a host starts logging it
+// first writes a `start` record holding the whole document it starts from, as
+// an insertion into an empty one; a log holds one session after another.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OpRecord {
+    pub revision: u64,
+    // Client id of the peer that made the change, None for the host itself
+    pub author: Option<String>,
+    // Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub changes: Vec<TextChange>,
+    // Whether this record begins a session. Logs written before sessions
+    // were marked hold only one, begun by the first record.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub start: bool,
+}
+
+impl OpRecord {
+    pub fn new(revision: u64, author: Option<String>, changes: Vec<TextChange>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        OpRecord { revision, author, timestamp, changes, start: false }
+    }
+}
+
+// Append-only JSON lines file of `OpRecord`s
+pub struct OpLogWriter {
+    file: File,
+}
+
+impl OpLogWriter {
+    // Begin a session in the log at `path` from `text` at `revision`, after
+    // whatever earlier sessions the log already holds
+    pub fn open(path: &Path, text: &str, revision: u64) -> io::Result<Self> {
+        let mut writer = OpLogWriter { file: OpenOptions::new().create(true).append(true).open(path)? };
+        let start = TextChange { text: text.to_string(), start: 0, end: 0 };
+        writer.append(&OpRecord { start: true, ..OpRecord::new(revision, None, vec![start]) })?;
+        Ok(writer)
+    }
+
+    pub fn append(&mut self, record: &OpRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        // One write per record so a crash never leaves half a line behind a
+        // complete one
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<OpRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e))
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// The records of each session in the log, oldest first
+pub fn sessions(records: &[OpRecord]) -> Vec<&[OpRecord]> {
+    let mut sessions = Vec::new();
+    let mut rest = records;
+    while !rest.is_empty() {
+        let len = rest[1..].iter().position(|record| record.start).map_or(rest.len(), |n| n + 1);
+        let (session, after) = rest.split_at(len);
+        sessions.push(session);
+        rest = after;
+    }
+    sessions
+}
+
+// Rebuild the document as it was at `revision` (the latest one when None) of
+// a session
+pub fn replay(records: &[OpRecord], revision: Option<u64>) -> Result<String, OtError> {
+    if let (Some(revision), Some(first), Some(last)) = (revision, records.first(), records.last()) {
+        if revision < first.revision || revision > last.revision {
+            return Err(OtError::UnknownRevision(revision));
+        }
+    }
+
+    let mut text = String::new();
+    for record in records {
+        if revision.is_some_and(|revision| record.revision > revision) {
+            break;
+        }
+        text = Operation::from_changes(text.len(), &record.changes)?.apply(&text)?;
+    }
+    Ok(text)
+}
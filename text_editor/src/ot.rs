@@ -0,0 +1,290 @@
+use std::fmt;
+
+use crate::protocol::TextChange;
+
+// Operational transformation over byte offsets, modelled on ot.js.
+//
+// An `Operation` walks the whole document: it retains, inserts or deletes
+// bytes in order, so it knows the length of the document it applies to
+// (`base_len`) and the length it produces (`target_len`).
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Operation {
+    components: Vec<Component>,
+    base_len: usize,
+    target_len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtError {
+    // The operation was made for a document of a different length
+    LengthMismatch { expected: usize, actual: usize },
+    // A change range is reversed, overlaps another one or ends past the document
+    InvalidRange { start: usize, end: usize, len: usize },
+    // An offset falls inside a multi-byte UTF-8 character
+    NotCharBoundary(usize),
+    // A change was made against a revision the host does not know about
+    UnknownRevision(u64),
+    // A change would grow the document past the size the host allows
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for OtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtError::LengthMismatch { expected, actual } => {
+                write!(f, "operation expects a document of {} bytes, got {}", expected, actual)
+            }
+            OtError::InvalidRange { start, end, len } => {
+                write!(f, "invalid range {}..{} for a document of {} bytes", start, end, len)
+            }
+            OtError::NotCharBoundary(offset) => write!(f, "offset {} is not on a character boundary", offset),
+            OtError::UnknownRevision(revision) => write!(f, "unknown revision {}", revision),
+            OtError::TooLarge { len, max } => {
+                write!(f, "document would grow to {} bytes, over the limit of {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OtError {}
+
+impl Operation {
+    pub fn new() -> Self {
+        Operation::default()
+    }
+
+    pub fn base_len(&self) -> usize {
+        self.base_len
+    }
+
+    pub fn target_len(&self) -> usize {
+        self.target_len
+    }
+
+    pub fn retain(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        self.target_len += n;
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Retain(n));
+        }
+        self
+    }
+
+    pub fn insert(&mut self, text: &str) -> &mut Self {
+        if text.is_empty() {
+            return self;
+        }
+        self.target_len += text.len();
+
+        // Keep inserts in front of deletes so equal operations compare equal
+        let len = self.components.len();
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] => last.push_str(text),
+            [.., Component::Insert(prev), Component::Delete(_)] => prev.push_str(text),
+            [.., Component::Delete(_)] => self.components.insert(len - 1, Component::Insert(text.to_string())),
+            _ => self.components.push(Component::Insert(text.to_string())),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Delete(n));
+        }
+        self
+    }
+
+    // Build an operation from replace-style changes whose offsets all refer to
+    // the same document of `base_len` bytes. Changes may come in any order but
+    // must not overlap.
+    pub fn from_changes(base_len: usize, changes: &[TextChange]) -> Result<Operation, OtError> {
+        let mut sorted: Vec<&TextChange> = changes.iter().collect();
+        sorted.sort_by_key(|change| (change.start, change.end));
+
+        let mut op = Operation::new();
+        let mut pos = 0;
+        for change in sorted {
+            if change.start < pos || change.start > change.end || change.end > base_len {
+                return Err(OtError::InvalidRange { start: change.start, end: change.end, len: base_len });
+            }
+            op.retain(change.start - pos);
+            op.delete(change.end - change.start);
+            op.insert(&change.text);
+            pos = change.end;
+        }
+        op.retain(base_len - pos);
+        Ok(op)
+    }
+
+    // Replace the whole document, used when a change can't be described precisely
+    pub fn replace_all(base_len: usize, text: &str) -> Operation {
+        let mut op = Operation::new();
+        op.delete(base_len);
+        op.insert(text);
+        op
+    }
+
+    // Express the operation as replace-style changes against its base document
+    pub fn to_changes(&self) -> Vec<TextChange> {
+        let mut changes: Vec<TextChange> = Vec::new();
+        let mut pos = 0;
+        let mut current: Option<TextChange> = None;
+
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => {
+                    changes.extend(current.take());
+                    pos += n;
+                }
+                Component::Insert(text) => {
+                    current
+                        .get_or_insert_with(|| TextChange { text: String::new(), start: pos, end: pos })
+                        .text
+                        .push_str(text);
+                }
+                Component::Delete(n) => {
+                    pos += n;
+                    current
+                        .get_or_insert_with(|| TextChange { text: String::new(), start: pos - n, end: pos })
+                        .end = pos;
+                }
+            }
+        }
+        changes.extend(current);
+        changes
+    }
+
+    pub fn apply(&self, text: &str) -> Result<String, OtError> {
+        if text.len() != self.base_len {
+            return Err(OtError::LengthMismatch { expected: self.base_len, actual: text.len() });
+        }
+
+        let mut result = String::with_capacity(self.target_len);
+        let mut pos = 0;
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => {
+                    result.push_str(text.get(pos..pos + n).ok_or(OtError::NotCharBoundary(pos + n))?);
+                    pos += n;
+                }
+                Component::Insert(inserted) => {
+                    if !text.is_char_boundary(pos) {
+                        return Err(OtError::NotCharBoundary(pos));
+                    }
+                    result.push_str(inserted);
+                }
+                Component::Delete(n) => {
+                    text.get(pos..pos + n).ok_or(OtError::NotCharBoundary(pos + n))?;
+                    pos += n;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // Given concurrent operations `a` and `b` on the same document, produce
+    // `(a', b')` so that applying `a` then `b'` equals applying `b` then `a'`.
+    // When both insert at the same position `a`'s text ends up first.
+    pub fn transform(a: &Operation, b: &Operation) -> Result<(Operation, Operation), OtError> {
+        if a.base_len != b.base_len {
+            return Err(OtError::LengthMismatch { expected: a.base_len, actual: b.base_len });
+        }
+
+        let mut a_prime = Operation::new();
+        let mut b_prime = Operation::new();
+        let mut iter_a = a.components.iter().cloned();
+        let mut iter_b = b.components.iter().cloned();
+        let mut comp_a = iter_a.next();
+        let mut comp_b = iter_b.next();
+
+        loop {
+            match (&comp_a, &comp_b) {
+                (None, None) => break,
+                (Some(Component::Insert(text)), _) => {
+                    a_prime.insert(text);
+                    b_prime.retain(text.len());
+                    comp_a = iter_a.next();
+                }
+                (_, Some(Component::Insert(text))) => {
+                    a_prime.retain(text.len());
+                    b_prime.insert(text);
+                    comp_b = iter_b.next();
+                }
+                (None, _) | (_, None) => {
+                    return Err(OtError::LengthMismatch { expected: a.base_len, actual: b.base_len });
+                }
+                (Some(Component::Retain(x)), Some(Component::Retain(y))) => {
+                    let n = (*x).min(*y);
+                    a_prime.retain(n);
+                    b_prime.retain(n);
+                    (comp_a, comp_b) = (shorten(comp_a, n, &mut iter_a), shorten(comp_b, n, &mut iter_b));
+                }
+                (Some(Component::Delete(x)), Some(Component::Delete(y))) => {
+                    // Both deleted the same bytes, nothing left to do for either side
+                    let n = (*x).min(*y);
+                    (comp_a, comp_b) = (shorten(comp_a, n, &mut iter_a), shorten(comp_b, n, &mut iter_b));
+                }
+                (Some(Component::Delete(x)), Some(Component::Retain(y))) => {
+                    let n = (*x).min(*y);
+                    a_prime.delete(n);
+                    (comp_a, comp_b) = (shorten(comp_a, n, &mut iter_a), shorten(comp_b, n, &mut iter_b));
+                }
+                (Some(Component::Retain(x)), Some(Component::Delete(y))) => {
+                    let n = (*x).min(*y);
+                    b_prime.delete(n);
+                    (comp_a, comp_b) = (shorten(comp_a, n, &mut iter_a), shorten(comp_b, n, &mut iter_b));
+                }
+            }
+        }
+
+        Ok((a_prime, b_prime))
+    }
+
+    // Where a byte offset in the base document ends up after this operation
+    pub fn transform_index(&self, index: usize) -> usize {
+        let mut new_index = index;
+        let mut pos = 0;
+        for component in &self.components {
+            if pos > index {
+                break;
+            }
+            match component {
+                Component::Retain(n) => pos += n,
+                Component::Insert(text) => new_index += text.len(),
+                Component::Delete(n) => {
+                    new_index -= (index - pos).min(*n);
+                    pos += n;
+                }
+            }
+        }
+        new_index
+    }
+}
+
+// Consume `n` bytes of a retain or delete, moving on to the next component when it is used up
+fn shorten(component: Option<Component>, n: usize, rest: &mut impl Iterator<Item = Component>) -> Option<Component> {
+    match component {
+        Some(Component::Retain(len)) if len > n => Some(Component::Retain(len - n)),
+        Some(Component::Delete(len)) if len > n => Some(Component::Delete(len - n)),
+        _ => rest.next(),
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use crate::ot::Operation;
+
+// Where someone else in the session has their cursor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collaborator {
+    pub name: String,
+    // The file they have open, empty when it has no name yet
+    pub buffer: String,
+    // Byte offset into our copy of the document
+    pub offset: usize,
+}
+
+// Everyone else's cursor, by the id the host gave them
+#[derive(Default)]
+pub struct Presence {
+    collaborators: BTreeMap<String, Collaborator>,
+}
+
+impl Presence {
+    pub fn update(&mut self, id: &str, name: &str, buffer: &str, offset: usize) {
+        let collaborator = Collaborator { name: name.to_string(), buffer: buffer.to_string(), offset };
+        self.collaborators.insert(id.to_string(), collaborator);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.collaborators.remove(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.collaborators.clear();
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Collaborator> {
+        self.collaborators.get(id)
+    }
+
+    // Id of the collaborator called `name`, or whose name starts with it
+    pub fn find(&self, name: &str) -> Option<&str> {
+        let name = name.trim().to_lowercase();
+        let mut matches = self.collaborators.iter().filter(|(_, c)| c.name.to_lowercase().starts_with(&name));
+        let exact = self.collaborators.iter().find(|(_, c)| c.name.to_lowercase() == name);
+        exact.or_else(|| matches.next()).map(|(id, _)| id.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Collaborator)> {
+        self.collaborators.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.collaborators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.collaborators.is_empty()
+    }
+
+    // Everyone is in the session's document, which is now `buffer`
+    pub fn rename_buffer(&mut self, buffer: &str) {
+        for collaborator in self.collaborators.values_mut() {
+            collaborator.buffer = buffer.to_string();
+        }
+    }
+
+    // Keep every cursor on the same text when the document changes
+    pub fn transform(&mut self, op: &Operation) {
+        for collaborator in self.collaborators.values_mut() {
+            collaborator.offset = op.transform_index(collaborator.offset);
+        }
+    }
+}
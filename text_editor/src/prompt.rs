@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::terminal::Key;
+
+// What the answer to a prompt is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PromptKind {
+    SaveAs,
+    Open,
+    GotoLine,
+    Search,
+    ExternalChange,
+    Recover,
+    Quit,
+    Follow,
+    Chat,
+    Comment,
+}
+
+impl PromptKind {
+    fn label(&self) -> &'static str {
+        match self {
+            PromptKind::SaveAs => "Save as: ",
+            PromptKind::Open => "Open file: ",
+            PromptKind::GotoLine => "Go to line: ",
+            PromptKind::Search => "Search: ",
+            PromptKind::ExternalChange => "File changed on disk: (r)eload, (k)eep the buffer or (m)erge? ",
+            PromptKind::Recover => "Found unsaved changes from an earlier session: (r)ecover or (d)iscard them? ",
+            PromptKind::Quit => "(s)ave and quit, (d)iscard changes and quit or (c)ancel? ",
+            PromptKind::Follow => "Follow: ",
+            PromptKind::Chat => "Say: ",
+            PromptKind::Comment => "Comment: ",
+        }
+    }
+
+    fn completes_paths(&self) -> bool {
+        matches!(self, PromptKind::SaveAs | PromptKind::Open)
+    }
+}
+
+pub enum PromptEvent {
+    Pending,
+    Submit(String),
+    Cancel,
+}
+
+// Earlier answers, kept separately for each kind of prompt
+#[derive(Default)]
+pub struct PromptHistory {
+    entries: HashMap<PromptKind, Vec<String>>,
+}
+
+impl PromptHistory {
+    pub fn get(&self, kind: PromptKind) -> &[String] {
+        self.entries.get(&kind).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn last(&self, kind: PromptKind) -> Option<&str> {
+        self.get(kind).last().map(String::as_str)
+    }
+
+    pub fn push(&mut self, kind: PromptKind, entry: &str) {
+        let entries = self.entries.entry(kind).or_default();
+        entries.retain(|e| e != entry);
+        entries.push(entry.to_string());
+    }
+}
+
+// Single line input shown in place of the status message
+pub struct Prompt {
+    pub kind: PromptKind,
+    label: String,
+    input: String,
+    // Byte offset into `input`
+    cursor: usize,
+    // Position while browsing the history with Up/Down, and what was typed before
+    history_index: Option<usize>,
+    draft: String,
+    // Candidates shown after an ambiguous completion
+    hint: String,
+}
+
+impl Prompt {
+    pub fn new(kind: PromptKind, initial: &str) -> Self {
+        Prompt::with_label(kind, kind.label().to_string(), initial)
+    }
+
+    pub fn with_label(kind: PromptKind, label: String, initial: &str) -> Self {
+        Prompt {
+            kind,
+            label,
+            input: initial.to_string(),
+            cursor: initial.len(),
+            history_index: None,
+            draft: String::new(),
+            hint: String::new(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        if self.hint.is_empty() {
+            format!("{}{}", self.label, self.input)
+        } else {
+            format!("{}{}  [{}]", self.label, self.input, self.hint)
+        }
+    }
+
+    // Column of the cursor on the prompt line
+    pub fn cursor_col(&self) -> usize {
+        self.label.chars().count() + self.input[..self.cursor].chars().count()
+    }
+
+    // The part of the prompt line that fits in `width` columns, and the
+    // cursor's column in it. The line is cut at the end, or at the start when
+    // typed input would put the cursor off screen.
+    pub fn render_within(&self, width: usize) -> (String, usize) {
+        let width = width.max(1);
+        let col = self.cursor_col();
+        if self.input.is_empty() {
+            return (self.render().chars().take(width).collect(), col.min(width - 1));
+        }
+        let skip = (col + 1).saturating_sub(width);
+        (self.render().chars().skip(skip).take(width).collect(), col - skip)
+    }
+
+    pub fn handle_key(&mut self, key: &Key, history: &[String]) -> PromptEvent {
+        self.hint.clear();
+        match key {
+            Key::Enter => return PromptEvent::Submit(self.input.clone()),
+            Key::Escape | Key::Ctrl(b'g') | Key::Ctrl(b'c') => return PromptEvent::Cancel,
+            Key::Char(c) => {
+                self.input.insert(self.cursor, *c);
+                self.cursor += c.len_utf8();
+            }
+            Key::Backspace => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.input.remove(self.cursor);
+                }
+            }
+            Key::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            Key::Left => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            Key::Right => {
+                if let Some(c) = self.input[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            Key::Home | Key::Ctrl(b'a') => self.cursor = 0,
+            Key::End | Key::Ctrl(b'e') => self.cursor = self.input.len(),
+            Key::Ctrl(b'u') => {
+                self.input.replace_range(..self.cursor, "");
+                self.cursor = 0;
+            }
+            Key::Up => self.browse_history(history, true),
+            Key::Down => self.browse_history(history, false),
+            Key::Ctrl(b'i') if self.kind.completes_paths() => self.complete(),
+            _ => {}
+        }
+        PromptEvent::Pending
+    }
+
+    fn browse_history(&mut self, history: &[String], older: bool) {
+        let index = match (self.history_index, older) {
+            (None, true) if !history.is_empty() => {
+                self.draft = self.input.clone();
+                Some(history.len() - 1)
+            }
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < history.len() => Some(i + 1),
+            (Some(_), false) => None,
+            (None, _) => return,
+        };
+
+        self.input = match index {
+            Some(i) => history[i].clone(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.history_index = index;
+        self.cursor = self.input.len();
+    }
+
+    fn complete(&mut self) {
+        let candidates = complete_path(&self.input);
+        match candidates.as_slice() {
+            [] => self.hint = "No match".to_string(),
+            [only] => self.input = only.clone(),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.as_str(), |common, candidate| common_prefix(common, candidate));
+                if common.len() > self.input.len() {
+                    self.input = common.to_string();
+                } else {
+                    let dir_len = self.input.rfind('/').map_or(0, |i| i + 1);
+                    let names: Vec<_> = candidates.iter().map(|c| &c[dir_len..]).collect();
+                    self.hint = names.join(" ");
+                }
+            }
+        }
+        self.cursor = self.input.len();
+    }
+}
+
+// Paths starting with `input`, directories with a trailing slash. Hidden
+// files are only offered once the name being completed starts with a dot.
+pub fn complete_path(input: &str) -> Vec<String> {
+    let (dir, prefix) = match input.rfind('/') {
+        Some(i) => (&input[..=i], &input[i + 1..]),
+        None => ("", input),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((i, _), _)| i)
+        .unwrap_or_else(|| a.len().min(b.len()));
+    &a[..len]
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// Both ends ping every HEARTBEAT_INTERVAL and drop a connection that has been
+// silent for PEER_TIMEOUT
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Replace the bytes in `start..end` of the document with `text`.
+// Offsets are byte offsets into the UTF-8 document.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TextChange {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Messages exchanged over the collaboration WebSocket.
+//
+// On connect the host sends a `Snapshot`. Afterwards peers send `Change`s
+// tagged with the revision they were made against, the host answers its
+// author with an `Ack` carrying the revision it was assigned, and relays the
+// (transformed) change to every other peer tagged with that same revision.
+//
+// A peer reconnecting with `ConnectParams` is instead sent every change after
+// the revision it last saw (its own as `Ack`s) followed by `Resumed`.
+// `SessionEnd` tells peers the host is going away and not to reconnect.
+//
+// Right after the first `Snapshot` the host sends `Joined` with the role it
+// granted and the id the peer's cursor is known by. Viewers receive everything
+// but may not change the document; what the host refuses is explained with an
+// `Error`. Peers report where their cursor is with `Cursor`, which the host
+// relays to everyone else as `Presence` (against the host's current revision),
+// and `Departed` once they leave. A session shares the host's one buffer, so
+// everyone is in it; `Presence` names its file, and is sent for everyone
+// again when the host opens another file or saves under a new name.
+//
+// `Chat` messages go to the host, which relays them to everyone, the sender
+// included, with the author filled in. Comments are anchored to the start of
+// a line: a peer proposes one with `AddComment` and the host announces it to
+// all with an id as `Comment`. `ResolveComment` removes one, going to the
+// host first and from there to everyone.
+//
+// A peer asks the host to write the document to disk with `Save`. Whenever
+// the host saves, on its own or on request, every peer is sent a `SaveResult`
+// with the revision that was written, or the error if saving failed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMessage {
+    Snapshot { text: String, revision: u64 },
+    Change { revision: u64, changes: Vec<TextChange> },
+    Ack { revision: u64 },
+    Resumed { revision: u64 },
+    SessionEnd { reason: String },
+    Joined { role: Role, id: String },
+    Error { message: String },
+    Cursor { revision: u64, offset: usize },
+    Presence {
+        id: String,
+        name: String,
+        offset: usize,
+        // File name of the buffer the cursor is in, empty while it has none
+        #[serde(default)]
+        buffer: String,
+    },
+    Departed { id: String },
+    Chat {
+        #[serde(default)]
+        author: String,
+        text: String,
+    },
+    AddComment { revision: u64, offset: usize, text: String },
+    Comment { id: u64, author: String, offset: usize, text: String },
+    ResolveComment { id: u64 },
+    Save,
+    SaveResult { revision: u64, error: Option<String> },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Editor,
+    Viewer,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        })
+    }
+}
+
+// Query parameters of the /ws endpoint
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ConnectParams {
+    // Stable id of the client across reconnections
+    pub client: Option<String>,
+    // Last revision the client has seen, to resume from
+    pub revision: Option<u64>,
+    // Shown to the others next to the client's cursor
+    pub name: Option<String>,
+    // Role asked for; editing may additionally require the host's `token`
+    pub role: Option<Role>,
+    pub token: Option<String>,
+}
+
+impl WsMessage {
+    // Older clients send bare `TextChange` objects without a revision;
+    // those are treated as changes against the current `revision`.
+    pub fn parse(text: &str, revision: u64) -> Option<WsMessage> {
+        if let Ok(message) = serde_json::from_str::<WsMessage>(text) {
+            return Some(message);
+        }
+        serde_json::from_str::<TextChange>(text)
+            .ok()
+            .map(|change| WsMessage::Change { revision, changes: vec![change] })
+    }
+}
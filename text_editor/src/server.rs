@@ -0,0 +1,280 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tracing::{debug, field, info_span, Instrument, Span};
+use warp::ws::{Message, WebSocket};
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::editor::Editor;
+use crate::hub::Hub;
+use crate::limits::RateLimiter;
+use crate::protocol::{ConnectParams, Role, WsMessage, HEARTBEAT_INTERVAL, PEER_TIMEOUT};
+use crate::session::{Outbox, PeerId, Session, OUTBOX_CAPACITY};
+use crate::tls::TlsConfig;
+
+// Tell a peer why what it sent was turned away
+fn send_error(editor: &Editor, peer: PeerId, message: String) {
+    if let Session::Host(host) = &editor.session {
+        host.send_to(peer, WsMessage::Error { message });
+    }
+}
+
+// Handle what a peer sent, on the editor's thread
+pub(crate) fn handle_message(editor: &mut Editor, peer: PeerId, text: &str) {
+    let revision = match &editor.session {
+        Session::Host(host) => host.revision(),
+        Session::Guest(_) => return,
+    };
+    let role = editor.peer_role(peer).unwrap_or(Role::Viewer);
+    let (revision, changes) = match WsMessage::parse(text, revision) {
+        Some(WsMessage::Change { .. } | WsMessage::Save) if role == Role::Viewer => {
+            let message = if editor.read_only { "the document is read-only" } else { "viewers cannot change the document" };
+            editor.metrics.change_rejected();
+            send_error(editor, peer, message.to_string());
+            // Undo whatever the viewer did to its copy
+            editor.resync_peer(peer);
+            editor.status_message = format!("Rejected change from viewer {}", peer);
+            return;
+        }
+        Some(WsMessage::Change { revision, changes }) => (revision, changes),
+        Some(WsMessage::Save) => {
+            editor.remote_save(peer);
+            return;
+        }
+        Some(WsMessage::Cursor { revision, offset }) => {
+            if let Err(e) = editor.peer_cursor(peer, revision, offset) {
+                debug!(error = %e, "ignoring cursor");
+            }
+            return;
+        }
+        // Viewers may talk and comment, just not edit
+        Some(WsMessage::Chat { text, .. }) => {
+            editor.peer_chat(peer, &text);
+            return;
+        }
+        Some(WsMessage::AddComment { revision, offset, text }) => {
+            if let Err(e) = editor.peer_comment(peer, revision, offset, &text) {
+                debug!(error = %e, "ignoring comment");
+            }
+            return;
+        }
+        Some(WsMessage::ResolveComment { id }) => {
+            if editor.may_resolve_comment(peer, id) {
+                editor.resolve_comment(id);
+            } else {
+                send_error(editor, peer, "only editors and its author can resolve a comment".to_string());
+            }
+            return;
+        }
+        None => {
+            debug!(text, "malformed message");
+            editor.metrics.message_dropped();
+            send_error(editor, peer, "malformed message".to_string());
+            return;
+        }
+        _ => {
+            debug!(text, "ignoring message");
+            return;
+        }
+    };
+
+    debug!(revision, ?changes, "applying change");
+
+    let start = Instant::now();
+    match editor.apply_remote_change(peer, revision, &changes) {
+        Ok(()) => {
+            editor.metrics.change_applied(start.elapsed());
+            // Inserted and removed, not the text itself, which may be long
+            let bytes: usize = changes.iter().map(|c| c.text.len() + c.end.saturating_sub(c.start)).sum();
+            if let Session::Host(host) = &editor.session {
+                let author = host.name(peer).unwrap_or_default();
+                editor.status_message = format!("{} changed {} byte{}", author, bytes, if bytes == 1 { "" } else { "s" });
+            }
+        }
+        Err(e) => {
+            debug!(error = %e, "change rejected");
+            editor.status_message = format!("Rejected change from peer {}: {}", peer, e);
+            editor.metrics.change_rejected();
+            send_error(editor, peer, format!("change rejected: {}", e));
+            editor.resync_peer(peer);
+        }
+    }
+}
+
+pub async fn handle_connection(ws: WebSocket, hub: Hub, params: ConnectParams) {
+    let (mut sender, mut receiver) = ws.split();
+    let (outbox, mut rx) = Outbox::new(OUTBOX_CAPACITY);
+
+    let Some(peer) = hub.connect(params, outbox.clone()).await else {
+        return;
+    };
+    Span::current().record("peer", field::display(peer));
+    let shutdown = hub.shutdown().token.clone();
+    let mut limiter = RateLimiter::new(hub.limits().messages_per_second, Instant::now());
+
+    // Write queued messages to the socket, and ping the peer every interval
+    // however busy it is: peers that never send anything of their own only
+    // show they are alive by answering
+    let mut writer = tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            let message = tokio::select! {
+                message = rx.recv() => match message.map(|m| serde_json::to_string(&m)) {
+                    Some(Ok(text)) => Message::text(text),
+                    Some(Err(_)) => continue,
+                    None => break,
+                },
+                _ = heartbeat.tick() => Message::ping(Vec::new()),
+            };
+            if sender.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = sender.close().await;
+    }
+    .in_current_span());
+
+    let mut last_seen = Instant::now();
+    let mut check = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = check.tick() => {
+                if last_seen.elapsed() > PEER_TIMEOUT {
+                    debug!("timed out");
+                    break;
+                }
+                continue;
+            }
+            // It resumes from where it got to when it reconnects
+            _ = outbox.overflowed() => {
+                debug!("fell too far behind, disconnecting");
+                hub.metrics().message_dropped();
+                break;
+            }
+            _ = outbox.closed() => {
+                debug!("disconnected by the host");
+                break;
+            }
+            _ = shutdown.cancelled() => break,
+        };
+        last_seen = Instant::now();
+        let Ok(text) = msg.to_str() else {
+            continue;
+        };
+
+        let max = hub.limits().max_message_size;
+        if text.len() > max {
+            debug!(len = text.len(), "dropping oversized message");
+            hub.metrics().message_dropped();
+            outbox.send(WsMessage::Error { message: format!("message of {} bytes is over the limit of {}", text.len(), max) });
+            continue;
+        }
+
+        // Pace the peer, leaving the rest of what it sends waiting in its socket
+        let wait = limiter.acquire(Instant::now());
+        if !wait.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+
+        // Waits while the document is busy, which slows down reading too
+        if !hub.message(peer, text.to_string()).await {
+            break;
+        }
+    }
+
+    // Once the document lets go of the peer's queue too, the writer flushes
+    // what is left and closes
+    drop(outbox);
+    hub.disconnect(peer).await;
+    if tokio::time::timeout(Duration::from_secs(1), &mut writer).await.is_err() {
+        writer.abort();
+    }
+    // Wait for the peer to answer the close, so the connection is not reset
+    // before it has read everything
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        while let Some(Ok(_)) = receiver.next().await {}
+    })
+    .await;
+}
+
+pub fn ws_routes(hub: Hub) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(warp::ws())
+        .and(warp::query::<ConnectParams>())
+        .and(warp::any().map(move || hub.clone()))
+        .map(|ws: warp::ws::Ws, params: ConnectParams, hub: Hub| {
+            let connections = hub.shutdown().connections.clone();
+            // Everything logged for the connection carries the peer and client
+            let span = info_span!("connection", peer = field::Empty, client = params.client.as_deref());
+            // Far larger messages are refused by the socket itself, closing
+            // the connection before they are read whole
+            ws.max_message_size(hub.limits().max_message_size.saturating_mul(4))
+                .on_upgrade(move |socket| connections.track_future(handle_connection(socket, hub, params).instrument(span)))
+        })
+}
+
+// `/health` answers whether the session is up, with a few numbers about it;
+// `/metrics` has them all for Prometheus
+pub fn status_routes(hub: Hub) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let health = warp::path("health").and(warp::path::end()).and(warp::get()).map({
+        let hub = hub.clone();
+        move || {
+            let metrics = hub.metrics();
+            let (status, code) = if hub.shutdown().token.is_cancelled() {
+                ("shutting down", StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                ("ok", StatusCode::OK)
+            };
+            let body = serde_json::json!({
+                "status": status,
+                "clients": metrics.connected_clients(),
+                "revision": metrics.revision(),
+                "document_size": metrics.document_size(),
+                "ops_per_second": metrics.ops_per_second(Instant::now()),
+            });
+            warp::reply::with_status(warp::reply::json(&body), code)
+        }
+    });
+    let metrics = warp::path("metrics").and(warp::path::end()).and(warp::get()).map(move || {
+        let body = hub.metrics().prometheus(Instant::now());
+        warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4")
+    });
+    health.or(metrics)
+}
+
+// Bind the collaboration endpoint to the editor behind `hub`, over wss://
+// when a TLS config is given. Returns the bound address (useful with port 0)
+// and the future running the server.
+pub fn bind_server(
+    hub: Hub,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(SocketAddr, BoxFuture<'static, ()>), warp::Error> {
+    let routes = ws_routes(hub.clone()).or(status_routes(hub));
+    match tls {
+        Some(tls) => {
+            let (addr, server) = warp::serve(routes)
+                .tls()
+                .cert(tls.cert_pem)
+                .key(tls.key_pem)
+                .try_bind_with_graceful_shutdown(addr, shutdown)?;
+            Ok((addr, server.boxed()))
+        }
+        None => {
+            let (addr, server) = warp::serve(routes).try_bind_with_graceful_shutdown(addr, shutdown)?;
+            Ok((addr, server.boxed()))
+        }
+    }
+}
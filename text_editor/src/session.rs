@@ -0,0 +1,472 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, error::TrySendError, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::oplog::{OpLogWriter, OpRecord};
+use crate::ot::{Operation, OtError};
+use crate::protocol::{Role, WsMessage};
+use crate::terminal;
+
+pub type PeerId = usize;
+
+// Messages a peer may have waiting to be written to its socket
+pub const OUTBOX_CAPACITY: usize = 1024;
+// Rejected messages a peer may send while the snapshot that undoes the first
+// is still waiting to be written, before it is disconnected
+pub const MAX_REJECTIONS: u32 = 16;
+
+// The queue between the document and the task writing to one peer's socket.
+// It is bounded so a peer that stops reading cannot make the host buffer
+// without end: once it is full the peer is cut off, and catches up when it
+// reconnects.
+#[derive(Clone)]
+pub struct Outbox {
+    queue: mpsc::Sender<WsMessage>,
+    overflowed: CancellationToken,
+    closed: CancellationToken,
+    // Messages queued so far
+    sent: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> (Outbox, mpsc::Receiver<WsMessage>) {
+        let (queue, rx) = mpsc::channel(capacity);
+        let outbox = Outbox { queue, overflowed: CancellationToken::new(), closed: CancellationToken::new(), sent: Arc::default() };
+        (outbox, rx)
+    }
+
+    // Queue a message without waiting; one that does not fit cuts the peer off.
+    // Nothing is queued after that, so the peer never sees a change without
+    // the ones before it.
+    pub fn send(&self, message: WsMessage) {
+        if self.has_overflowed() {
+            return;
+        }
+        match self.queue.try_send(message) {
+            Ok(()) => {
+                self.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Full(_)) => self.overflowed.cancel(),
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    // Messages that still fit
+    pub fn room(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    // Messages waiting to be written
+    pub fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    // Ask the connection to hang up
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+
+    pub fn has_overflowed(&self) -> bool {
+        self.overflowed.is_cancelled()
+    }
+
+    // Resolves once a message did not fit
+    pub async fn overflowed(&self) {
+        self.overflowed.cancelled().await
+    }
+}
+
+// Collaboration state of an editor: either it hosts the document and orders
+// everyone's changes, or it has joined another editor's session.
+pub enum Session {
+    Host(HostSession),
+    Guest(GuestSession),
+}
+
+// Bookkeeping for the editor that owns the document. Every change gets the
+// next revision number and is kept so that late changes from peers, made
+// against an older revision, can be transformed onto the current document.
+#[derive(Default)]
+pub struct HostSession {
+    revision: u64,
+    history: Vec<HistoryEntry>,
+    peers: HashMap<PeerId, Peer>,
+    next_peer_id: PeerId,
+    // Where history is also written to, if anywhere
+    log: Option<OpLogWriter>,
+}
+
+struct HistoryEntry {
+    op: Operation,
+    record: OpRecord,
+}
+
+struct Peer {
+    outbox: Outbox,
+    client_id: Option<String>,
+    name: String,
+    role: Role,
+    // How many messages the outbox had queued right after the last snapshot
+    // sent to undo a rejected message, and the rejections since
+    resynced_at: Option<usize>,
+    rejections: u32,
+}
+
+// What became of a peer's request to be sent the whole document again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resync {
+    Sent,
+    // The last snapshot has not been written yet, it will do
+    Pending,
+    // The peer kept sending rejected messages and was disconnected
+    Closed,
+}
+
+impl HostSession {
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // Write every change from now on to the op log at `path`, as a session
+    // starting from `text`, the document at the current revision
+    pub fn start_log(&mut self, path: &Path, text: &str) -> io::Result<()> {
+        self.log = Some(OpLogWriter::open(path, text, self.revision)?);
+        Ok(())
+    }
+
+    pub fn add_peer(&mut self, outbox: Outbox, client_id: Option<String>) -> PeerId {
+        let id = self.next_peer_id;
+        self.next_peer_id += 1;
+        debug!("Peer {} connected (client {:?})", id, client_id);
+        let peer = Peer { outbox, client_id, name: format!("peer {}", id), role: Role::Editor, resynced_at: None, rejections: 0 };
+        self.peers.insert(id, peer);
+        id
+    }
+
+    pub fn set_identity(&mut self, id: PeerId, name: Option<String>, role: Role) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.name = name.map(|name| terminal::printable(&name)).unwrap_or_else(|| format!("peer {}", id));
+            peer.role = role;
+        }
+    }
+
+    pub fn name(&self, id: PeerId) -> Option<&str> {
+        self.peers.get(&id).map(|peer| peer.name.as_str())
+    }
+
+    pub fn client_id(&self, id: PeerId) -> Option<&str> {
+        self.peers.get(&id)?.client_id.as_deref()
+    }
+
+    pub fn role(&self, id: PeerId) -> Option<Role> {
+        self.peers.get(&id).map(|peer| peer.role)
+    }
+
+    pub fn remove_peer(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+        debug!("Peer {} disconnected", id);
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    // Say goodbye to every peer; dropping them lets their connections close
+    pub fn end(&mut self, reason: &str) {
+        for (_, peer) in self.peers.drain() {
+            peer.outbox.send(WsMessage::SessionEnd { reason: reason.to_string() });
+        }
+    }
+
+    pub fn broadcast(&self, message: WsMessage) {
+        for peer in self.peers.values() {
+            peer.outbox.send(message.clone());
+        }
+    }
+
+    pub fn broadcast_except(&self, except: PeerId, message: WsMessage) {
+        for (_, peer) in self.peers.iter().filter(|(id, _)| **id != except) {
+            peer.outbox.send(message.clone());
+        }
+    }
+
+    pub fn send_to(&self, id: PeerId, message: WsMessage) {
+        if let Some(peer) = self.peers.get(&id) {
+            peer.outbox.send(message);
+        }
+    }
+
+    // Send a peer whose copy went astray the whole document, as `text` makes
+    // it. At most one snapshot waits in a peer's outbox, however much it sends.
+    pub fn resync(&mut self, id: PeerId, text: impl FnOnce() -> String) -> Resync {
+        let revision = self.revision;
+        let Some(peer) = self.peers.get_mut(&id) else {
+            return Resync::Pending;
+        };
+        // Everything queued after the snapshot is written after it, too
+        let pending = peer.resynced_at.is_some_and(|at| peer.outbox.queued() > peer.outbox.sent() - at);
+        if !pending {
+            peer.outbox.send(WsMessage::Snapshot { text: text(), revision });
+            peer.resynced_at = Some(peer.outbox.sent());
+            peer.rejections = 0;
+            return Resync::Sent;
+        }
+        peer.rejections += 1;
+        if peer.rejections < MAX_REJECTIONS {
+            return Resync::Pending;
+        }
+        debug!("Peer {} keeps sending rejected messages, disconnecting", id);
+        peer.outbox.close();
+        Resync::Closed
+    }
+
+    // Catch a reconnecting peer up on everything after `revision`. Changes it
+    // made itself are acknowledged rather than replayed. Returns false when
+    // the revision is unknown and the peer needs a snapshot instead.
+    pub fn resume_peer(&self, id: PeerId, revision: u64) -> bool {
+        let (Some(peer), Some(missed)) = (self.peers.get(&id), self.history.get(revision as usize..)) else {
+            return false;
+        };
+        // Too far behind to replay without overflowing
+        if missed.len() >= peer.outbox.room() {
+            return false;
+        }
+
+        for (entry, revision) in missed.iter().zip(revision + 1..) {
+            let message = if entry.record.author.is_some() && entry.record.author == peer.client_id {
+                WsMessage::Ack { revision }
+            } else {
+                WsMessage::Change { revision, changes: entry.record.changes.clone() }
+            };
+            peer.outbox.send(message);
+        }
+        peer.outbox.send(WsMessage::Resumed { revision: self.revision });
+        debug!("Peer {} resumed from revision {}, {} changes replayed", id, revision, missed.len());
+        true
+    }
+
+    // Length of the document at `revision`, given its current length
+    pub fn len_at(&self, revision: u64, current_len: usize) -> Result<usize, OtError> {
+        match self.history.get(revision as usize) {
+            Some(entry) => Ok(entry.op.base_len()),
+            None if revision == self.revision => Ok(current_len),
+            None => Err(OtError::UnknownRevision(revision)),
+        }
+    }
+
+    // Where an offset into the document at `revision` is now
+    pub fn transform_offset(&self, revision: u64, offset: usize) -> Result<usize, OtError> {
+        let concurrent = self
+            .history
+            .get(revision as usize..)
+            .ok_or(OtError::UnknownRevision(revision))?;
+        Ok(concurrent.iter().fold(offset, |offset, entry| entry.op.transform_index(offset)))
+    }
+
+    // Rebase a peer's change made against `revision` onto the current document
+    pub fn transform_incoming(&self, revision: u64, mut op: Operation) -> Result<Operation, OtError> {
+        let concurrent = self
+            .history
+            .get(revision as usize..)
+            .ok_or(OtError::UnknownRevision(revision))?;
+        for concurrent in concurrent {
+            op = Operation::transform(&op, &concurrent.op)?.0;
+        }
+        Ok(op)
+    }
+
+    // Record an applied change and relay it to every peer except its author
+    pub fn commit(&mut self, op: Operation, author: Option<PeerId>) -> u64 {
+        let changes = op.to_changes();
+        let client_id = author.and_then(|id| self.peers.get(&id)).and_then(|peer| peer.client_id.clone());
+        self.revision += 1;
+        let record = OpRecord::new(self.revision, client_id, changes.clone());
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.append(&record) {
+                error!("Failed to write op log, no longer logging: {}", e);
+                self.log = None;
+            }
+        }
+        self.history.push(HistoryEntry { op, record });
+
+        for (id, peer) in &self.peers {
+            let message = if Some(*id) == author {
+                WsMessage::Ack { revision: self.revision }
+            } else {
+                WsMessage::Change { revision: self.revision, changes: changes.clone() }
+            };
+            peer.outbox.send(message);
+        }
+        self.revision
+    }
+}
+
+// Client side of the protocol: at most one change is in flight waiting for the
+// host's acknowledgement, later local changes queue up behind it. Changes
+// from the host are transformed past both before being applied.
+pub struct GuestSession {
+    revision: u64,
+    outstanding: Option<Operation>,
+    buffer: VecDeque<Operation>,
+    outgoing: UnboundedSender<WsMessage>,
+    // False between losing the connection and the host confirming the resume;
+    // nothing is sent meanwhile
+    connected: bool,
+    // Messages other than changes that were queued when the connection
+    // dropped, sent again once the host is back
+    held: Vec<WsMessage>,
+}
+
+impl GuestSession {
+    pub fn new(revision: u64, outgoing: UnboundedSender<WsMessage>) -> Self {
+        GuestSession {
+            revision,
+            outstanding: None,
+            buffer: VecDeque::new(),
+            outgoing,
+            connected: true,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // Whether some local changes have not been acknowledged by the host yet
+    pub fn has_pending(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    // Length of the document as the host currently knows it
+    pub fn host_len(&self, local_len: usize) -> usize {
+        self.outstanding
+            .as_ref()
+            .map(|op| op.base_len())
+            .unwrap_or(local_len)
+    }
+
+    pub fn local(&mut self, op: Operation) {
+        if self.outstanding.is_none() {
+            self.send(&op);
+            self.outstanding = Some(op);
+        } else {
+            self.buffer.push_back(op);
+        }
+    }
+
+    pub fn ack(&mut self, revision: u64) {
+        self.revision = revision;
+        self.outstanding = self.buffer.pop_front();
+        if let Some(op) = &self.outstanding {
+            self.send(op);
+        }
+    }
+
+    // Transform a change from the host past our unacknowledged changes
+    pub fn remote(&mut self, revision: u64, mut op: Operation) -> Result<Operation, OtError> {
+        let pending = self.outstanding.iter_mut().chain(self.buffer.iter_mut());
+        for local in pending {
+            let (local_prime, op_prime) = Operation::transform(local, &op)?;
+            *local = local_prime;
+            op = op_prime;
+        }
+        self.revision = revision;
+        Ok(op)
+    }
+
+    // Where an offset into the host's document is in ours, which has our
+    // unacknowledged changes applied on top
+    pub fn transform_offset(&self, offset: usize) -> usize {
+        self.outstanding
+            .iter()
+            .chain(self.buffer.iter())
+            .fold(offset, |offset, op| op.transform_index(offset))
+    }
+
+    // Report our cursor, which is only meaningful to the host once it has all
+    // our changes. Returns false when it could not be sent.
+    pub fn send_cursor(&self, offset: usize) -> bool {
+        self.connected
+            && !self.has_pending()
+            && self.outgoing.send(WsMessage::Cursor { revision: self.revision, offset }).is_ok()
+    }
+
+    // Ask the host to save the document. Returns false while disconnected.
+    pub fn request_save(&self) -> bool {
+        self.send_message(WsMessage::Save)
+    }
+
+    // Send anything but a change. Returns false while disconnected.
+    pub fn send_message(&self, message: WsMessage) -> bool {
+        self.connected && self.outgoing.send(message).is_ok()
+    }
+
+    pub fn disconnected(&mut self) {
+        self.connected = false;
+    }
+
+    // Keep a message that never reached the host. Changes are not kept, they
+    // are still outstanding, and neither are cursors, which the editor
+    // reports again once the host is back.
+    pub fn hold(&mut self, message: WsMessage) {
+        if !matches!(message, WsMessage::Change { .. } | WsMessage::Cursor { .. }) {
+            self.held.push(message);
+        }
+    }
+
+    // The host finished replaying what we missed while disconnected. Whatever
+    // is still outstanding never reached it, so send it against the current
+    // revision.
+    pub fn resumed(&mut self, revision: u64) {
+        self.revision = revision;
+        self.connected = true;
+        if let Some(op) = &self.outstanding {
+            self.send(op);
+        }
+        self.send_held();
+    }
+
+    // The host sent the whole document instead. Returns whether local
+    // changes were thrown away because the connection dropped; when the host
+    // resyncs a connected peer, it has already said why.
+    pub fn reset(&mut self, revision: u64) -> bool {
+        let lost = self.has_pending() && !self.connected;
+        self.revision = revision;
+        self.outstanding = None;
+        self.buffer.clear();
+        self.connected = true;
+        self.send_held();
+        lost
+    }
+
+    fn send_held(&mut self) {
+        for message in self.held.drain(..) {
+            let _ = self.outgoing.send(message);
+        }
+    }
+
+    fn send(&self, op: &Operation) {
+        if !self.connected {
+            return;
+        }
+        let _ = self.outgoing.send(WsMessage::Change {
+            revision: self.revision,
+            changes: op.to_changes(),
+        });
+    }
+}
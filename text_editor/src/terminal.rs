@@ -0,0 +1,424 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, stdout, stdin, IsTerminal, Write, Read};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+pub const SHOW_CURSOR: &str = "\x1b[?25h";
+
+// Escape sequence moving the cursor to a zero-based row and column
+pub fn cursor_position(row: usize, col: usize) -> String {
+    format!("\x1b[{};{}H", row + 1, col + 1)
+}
+
+// `text` without control characters, so what others send cannot move the
+// cursor, change colours or clear the screen. Line breaks and tabs become spaces.
+pub fn printable(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\n' | '\r' | '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+// Terminal handling
+pub struct Terminal;
+
+impl Terminal {
+    pub fn enter_raw_mode() -> io::Result<()> {
+        // Disable canonical mode, echo, flow control (Ctrl+S/Ctrl+Q) and
+        // signals (Ctrl+C/Ctrl+Z), and give up reading after a tenth of a
+        // second so a lone Escape is not mistaken for the start of a sequence
+        Command::new("stty")
+            .args(["-icanon", "-echo", "-ixon", "-isig", "min", "0", "time", "1"])
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()?;
+        Ok(())
+    }
+
+    pub fn exit_raw_mode() -> io::Result<()> {
+        // Re-enable canonical mode, echo and signals
+        Command::new("stty")
+            .args(["icanon", "echo", "isig"])
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()?;
+        Ok(())
+    }
+
+    pub fn clear_screen() -> io::Result<()> {
+        print!("{}", CLEAR_SCREEN);
+        stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn hide_cursor() -> io::Result<()> {
+        print!("\x1b[?25l");
+        stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn show_cursor() -> io::Result<()> {
+        print!("{}", SHOW_CURSOR);
+        stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn move_cursor(row: usize, col: usize) -> io::Result<()> {
+        print!("{}", cursor_position(row, col));
+        stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn get_terminal_size() -> io::Result<(usize, usize)> {
+        let output = Command::new("tput")
+            .args(["lines"])
+            .output()?;
+        let rows = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<usize>()
+            .unwrap_or(24);
+
+        let output = Command::new("tput")
+            .args(["cols"])
+            .output()?;
+        let cols = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<usize>()
+            .unwrap_or(80);
+
+        Ok((rows, cols))
+    }
+
+    // The next key, or None when none was pressed for a while
+    pub fn read_key() -> io::Result<Option<Key>> {
+        let mut stdin = stdin().lock();
+        let mut lead = [0; 1];
+        if stdin.read(&mut lead)? == 0 {
+            // Reads time out in raw mode; anything but a terminal has ended
+            return if stdin.is_terminal() { Ok(None) } else { Err(io::ErrorKind::UnexpectedEof.into()) };
+        }
+        Key::decode(&mut lead.chain(stdin)).map(Some)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Char(char),
+    Ctrl(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Delete,
+    Escape,
+}
+
+impl Key {
+    // Read one key press from raw terminal input. A read that returns
+    // nothing right after Escape means it was pressed on its own.
+    pub fn decode(input: &mut impl Read) -> io::Result<Key> {
+        let mut buffer = [0; 1];
+        input.read_exact(&mut buffer)?;
+        
+        match buffer[0] {
+            b'\x1b' => {
+                // Escape sequence - read more bytes, if they come
+                let mut next = || -> Option<u8> {
+                    let mut byte = [0; 1];
+                    matches!(input.read(&mut byte), Ok(1)).then_some(byte[0])
+                };
+                if next() != Some(b'[') {
+                    return Ok(Key::Escape);
+                }
+                match next() {
+                    Some(b'A') => Ok(Key::Up),
+                    Some(b'B') => Ok(Key::Down),
+                    Some(b'C') => Ok(Key::Right),
+                    Some(b'D') => Ok(Key::Left),
+                    Some(b'H') => Ok(Key::Home),
+                    Some(b'F') => Ok(Key::End),
+                    _ => Ok(Key::Escape),
+                }
+            }
+            b'\r' | b'\n' => Ok(Key::Enter),
+            b'\x7f' | b'\x08' => Ok(Key::Backspace),
+            b'\x04' => Ok(Key::Delete),
+            1 => Ok(Key::Ctrl(b'a')),   // Ctrl+A
+            2 => Ok(Key::Ctrl(b'b')),   // Ctrl+B
+            3 => Ok(Key::Ctrl(b'c')),   // Ctrl+C
+            5 => Ok(Key::Ctrl(b'e')),   // Ctrl+E
+            6 => Ok(Key::Ctrl(b'f')),   // Ctrl+F
+            7 => Ok(Key::Ctrl(b'g')),   // Ctrl+G
+            9 => Ok(Key::Ctrl(b'i')),   // Ctrl+I (Tab)
+            11 => Ok(Key::Ctrl(b'k')),  // Ctrl+K
+            12 => Ok(Key::Ctrl(b'l')),  // Ctrl+L
+            14 => Ok(Key::Ctrl(b'n')),  // Ctrl+N
+            15 => Ok(Key::Ctrl(b'o')),  // Ctrl+O
+            16 => Ok(Key::Ctrl(b'p')),  // Ctrl+P
+            17 => Ok(Key::Ctrl(b'q')),  // Ctrl+Q
+            18 => Ok(Key::Ctrl(b'r')),  // Ctrl+R
+            19 => Ok(Key::Ctrl(b's')),  // Ctrl+S
+            20 => Ok(Key::Ctrl(b't')),  // Ctrl+T
+            21 => Ok(Key::Ctrl(b'u')),  // Ctrl+U
+            22 => Ok(Key::Ctrl(b'v')),  // Ctrl+V
+            23 => Ok(Key::Ctrl(b'w')),  // Ctrl+W
+            24 => Ok(Key::Ctrl(b'x')),  // Ctrl+X
+            25 => Ok(Key::Ctrl(b'y')),  // Ctrl+Y
+            26 => Ok(Key::Ctrl(b'z')),  // Ctrl+Z
+            c @ 32..=126 => Ok(Key::Char(c as char)),
+            c @ 0xc2..=0xf4 => Ok(Key::decode_utf8(c, input)),
+            c => Ok(Key::Ctrl(c)),
+        }
+    }
+
+    // The rest of a UTF-8 character whose first byte is `lead`. A character
+    // cut short or not UTF-8 at all is taken as the lead byte alone.
+    fn decode_utf8(lead: u8, input: &mut impl Read) -> Key {
+        let len = match lead {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        let mut bytes = [lead, 0, 0, 0];
+        for byte in &mut bytes[1..len] {
+            let mut next = [0; 1];
+            if !matches!(input.read(&mut next), Ok(1)) || next[0] & 0xc0 != 0x80 {
+                return Key::Ctrl(lead);
+            }
+            *byte = next[0];
+        }
+        std::str::from_utf8(&bytes[..len]).ok().and_then(|s| s.chars().next()).map_or(Key::Ctrl(lead), Key::Char)
+    }
+}
+
+// Where the editor draws its frames and reads keys from
+pub trait Backend: Send + Sync {
+    // Rows and columns available
+    fn size(&self) -> io::Result<(usize, usize)>;
+    // Take over the terminal for editing, and give it back
+    fn enter_raw_mode(&self) -> io::Result<()>;
+    fn exit_raw_mode(&self) -> io::Result<()>;
+    // Show a frame: text and escape sequences, starting from a cleared screen
+    fn draw(&self, frame: &str) -> io::Result<()>;
+    // Wait for the next key, or return None after a while without one so
+    // the caller can check whether it is still wanted
+    fn read_key(&self) -> io::Result<Option<Key>>;
+}
+
+// The terminal the editor was started in
+pub struct Tty;
+
+impl Backend for Tty {
+    fn size(&self) -> io::Result<(usize, usize)> {
+        Terminal::get_terminal_size()
+    }
+
+    fn enter_raw_mode(&self) -> io::Result<()> {
+        Terminal::enter_raw_mode()
+    }
+
+    fn exit_raw_mode(&self) -> io::Result<()> {
+        Terminal::exit_raw_mode()?;
+        Terminal::show_cursor()
+    }
+
+    fn draw(&self, frame: &str) -> io::Result<()> {
+        let mut out = stdout().lock();
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+
+    fn read_key(&self) -> io::Result<Option<Key>> {
+        Terminal::read_key()
+    }
+}
+
+// No terminal at all, when serving the document to remote clients only:
+// frames go nowhere and no key ever comes
+pub struct Detached;
+
+impl Backend for Detached {
+    fn size(&self) -> io::Result<(usize, usize)> {
+        Ok((24, 80))
+    }
+
+    fn enter_raw_mode(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn exit_raw_mode(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn draw(&self, _frame: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_key(&self) -> io::Result<Option<Key>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "no terminal attached"))
+    }
+}
+
+// A terminal in memory, for running the editor without a TTY: keys are
+// queued up front and every frame drawn is kept. Clones share the screen.
+#[derive(Clone)]
+pub struct Headless {
+    size: (usize, usize),
+    state: Arc<Mutex<HeadlessState>>,
+}
+
+#[derive(Default)]
+struct HeadlessState {
+    keys: VecDeque<Key>,
+    frames: Vec<String>,
+    raw: bool,
+}
+
+impl Headless {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Headless { size: (rows, cols), state: Arc::default() }
+    }
+
+    pub fn push_key(&self, key: Key) {
+        self.state.lock().keys.push_back(key);
+    }
+
+    pub fn push_keys(&self, keys: impl IntoIterator<Item = Key>) {
+        self.state.lock().keys.extend(keys);
+    }
+
+    pub fn frames(&self) -> Vec<String> {
+        self.state.lock().frames.clone()
+    }
+
+    pub fn last_frame(&self) -> Option<String> {
+        self.state.lock().frames.last().cloned()
+    }
+
+    // What the screen shows after the last frame
+    pub fn screen(&self) -> Option<Screen> {
+        let (rows, cols) = self.size;
+        self.last_frame().map(|frame| Screen::render(&frame, rows, cols))
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.state.lock().raw
+    }
+}
+
+impl Backend for Headless {
+    fn size(&self) -> io::Result<(usize, usize)> {
+        Ok(self.size)
+    }
+
+    fn enter_raw_mode(&self) -> io::Result<()> {
+        self.state.lock().raw = true;
+        Ok(())
+    }
+
+    fn exit_raw_mode(&self) -> io::Result<()> {
+        self.state.lock().raw = false;
+        Ok(())
+    }
+
+    fn draw(&self, frame: &str) -> io::Result<()> {
+        self.state.lock().frames.push(frame.to_string());
+        Ok(())
+    }
+
+    // Running out of queued keys is the end of input
+    fn read_key(&self) -> io::Result<Option<Key>> {
+        let key = self.state.lock().keys.pop_front();
+        key.map(Some).ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no more keys"))
+    }
+}
+
+// The text a terminal shows after drawing a frame, and where its cursor is.
+// Only the escape sequences the editor writes are understood; attributes such
+// as reverse video are dropped, and text past the right edge is cut off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    pub rows: Vec<String>,
+    pub cursor: (usize, usize),
+}
+
+impl Screen {
+    pub fn render(frame: &str, rows: usize, cols: usize) -> Screen {
+        let rows = rows.max(1);
+        let mut grid = vec![vec![' '; cols]; rows];
+        let (mut row, mut col) = (0, 0);
+        let mut chars = frame.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => {
+                    if chars.next_if_eq(&'[').is_none() {
+                        continue;
+                    }
+                    let mut params = String::new();
+                    let mut command = None;
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            command = Some(c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                    match command {
+                        Some('H') => {
+                            let mut numbers = params.split(';').map(|n| n.parse::<usize>().unwrap_or(1).max(1));
+                            row = (numbers.next().unwrap_or(1) - 1).min(rows - 1);
+                            col = numbers.next().unwrap_or(1) - 1;
+                        }
+                        Some('J') if params == "2" => grid.iter_mut().for_each(|line| line.fill(' ')),
+                        // Attributes and cursor visibility
+                        _ => {}
+                    }
+                }
+                '\r' => col = 0,
+                '\n' => {
+                    if row + 1 < rows {
+                        row += 1;
+                    } else {
+                        grid.remove(0);
+                        grid.push(vec![' '; cols]);
+                    }
+                }
+                c => {
+                    if col < cols {
+                        grid[row][col] = c;
+                    }
+                    col += 1;
+                }
+            }
+        }
+
+        Screen {
+            rows: grid.into_iter().map(|line| line.into_iter().collect()).collect(),
+            cursor: (row, col.min(cols.saturating_sub(1))),
+        }
+    }
+}
+
+// One line per row without trailing blanks, then the cursor position
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in &self.rows {
+            writeln!(f, "{}", row.trim_end())?;
+        }
+        write!(f, "cursor {}:{}", self.cursor.0, self.cursor.1)
+    }
+}
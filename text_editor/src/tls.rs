@@ -0,0 +1,97 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tokio_tungstenite::Connector;
+use tracing::debug;
+
+// Certificate material for serving the collaboration endpoint over wss://
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub cert_pem: Vec<u8>,
+    pub key_pem: Vec<u8>,
+}
+
+impl TlsConfig {
+    // Load a PEM encoded certificate chain and private key from disk
+    pub fn from_files(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let cert_pem = fs::read(cert_path).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to read TLS certificate {}: {}", cert_path.display(), e))
+        })?;
+        let key_pem = fs::read(key_path).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to read TLS key {}: {}", key_path.display(), e))
+        })?;
+
+        Ok(TlsConfig { cert_pem, key_pem })
+    }
+
+    // Generate a throwaway self-signed certificate for local development.
+    // Clients have to trust the certificate explicitly, see `write_cert`.
+    pub fn self_signed(hosts: &[String]) -> io::Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(hosts.to_vec())
+            .map_err(|e| io::Error::other(format!("failed to generate certificate: {}", e)))?;
+        let cert_pem = cert
+            .serialize_pem()
+            .map_err(|e| io::Error::other(format!("failed to encode certificate: {}", e)))?;
+
+        debug!("Generated self-signed certificate for {:?}", hosts);
+
+        Ok(TlsConfig {
+            cert_pem: cert_pem.into_bytes(),
+            key_pem: cert.serialize_private_key_pem().into_bytes(),
+        })
+    }
+
+    // Write the certificate (never the key) so clients can add it to their
+    // trust store. Whatever was at `path`, a symlink included, is replaced
+    // rather than written through.
+    pub fn write_cert(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(&self.cert_pem)
+    }
+}
+
+// Where the development certificate of the server on `port` is written: in
+// the user's own state directory, not the shared temp directory, and apart
+// from those of other servers running at the same time
+pub fn self_signed_cert_path(port: u16) -> Option<PathBuf> {
+    Some(crate::logging::state_dir()?.join(format!("dev-cert-{}.pem", port)))
+}
+
+// Client side TLS settings trusting the PEM certificates in `ca_pem`,
+// e.g. the development certificate of a host started with --tls-self-signed
+pub fn client_config(ca_pem: &[u8]) -> io::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &ca_pem[..]) {
+        roots
+            .add(cert?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid CA certificate: {}", e)))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no certificates found in CA file"));
+    }
+
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+pub fn client_connector(ca_path: &Path) -> io::Result<Connector> {
+    let ca_pem = fs::read(ca_path).map_err(|e| {
+        io::Error::new(e.kind(), format!("failed to read CA certificate {}: {}", ca_path.display(), e))
+    })?;
+    Ok(Connector::Rustls(std::sync::Arc::new(client_config(&ca_pem)?)))
+}
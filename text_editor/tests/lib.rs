@@ -1,10 +1,28 @@
+use std::io;
+
+use tokio::sync::mpsc;
+use warp::Filter;
+
+use text_editor::buffer::index_to_line_column;
+use text_editor::ot::{Operation, OtError};
+use text_editor::protocol::{ConnectParams, Role, TextChange, WsMessage};
+use text_editor::hub::Hub;
+use text_editor::server::{bind_server, handle_connection};
+use text_editor::session::{HostSession, Outbox, PeerId, Resync, Session, OUTBOX_CAPACITY};
+use text_editor::terminal::Headless;
+use text_editor::{cli, client, editor, file, oplog, ot, prompt, session, tls, Editor, Key, Terminal};
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use parking_lot::Mutex;
     use std::fs;
     use std::io::Write;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
     use tempfile::NamedTempFile;
-    use tokio_test;
+    use tokio::task::JoinHandle;
 
     // Test utility functions
     fn create_test_file(content: &str) -> NamedTempFile {
@@ -13,6 +31,101 @@ mod tests {
         file
     }
 
+    // An editor on a 24x80 terminal in memory
+    fn headless_editor(filename: Option<String>) -> io::Result<Editor> {
+        Editor::with_terminal(filename, Arc::new(Headless::new(24, 80)))
+    }
+
+    // An editor on `dir`/notes.txt, which holds `content`
+    fn open(dir: &tempfile::TempDir, content: &str) -> (PathBuf, Editor) {
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, content).unwrap();
+        let editor = headless_editor(Some(path.to_string_lossy().to_string())).unwrap();
+        (path, editor)
+    }
+
+    fn type_str(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            editor.process_keypress(&Key::Char(c)).unwrap();
+        }
+    }
+
+    // Type `text` into the open prompt and confirm it
+    fn answer(editor: &mut Editor, text: &str) {
+        type_str(editor, text);
+        editor.process_keypress(&Key::Enter).unwrap();
+    }
+
+    fn change(text: &str, start: usize, end: usize) -> TextChange {
+        TextChange { text: text.to_string(), start, end }
+    }
+
+    // A peer's queue, as the server would make it
+    fn outbox() -> (Outbox, mpsc::Receiver<WsMessage>) {
+        Outbox::new(OUTBOX_CAPACITY)
+    }
+
+    // Serve `host` on a free local port
+    fn serve(host: Editor) -> (Hub, SocketAddr, JoinHandle<()>) {
+        let host = Hub::start(host);
+        let (addr, server) = bind_server(host.clone(), ([127, 0, 0, 1], 0).into(), None, futures::future::pending())
+            .expect("Failed to bind server");
+        (host, addr, tokio::spawn(server))
+    }
+
+    // A host editing `text`, served on a free local port
+    fn host(text: &str) -> (Hub, SocketAddr, JoinHandle<()>) {
+        let mut host = headless_editor(None).expect("Failed to create editor");
+        host.set_text(text);
+        serve(host)
+    }
+
+    // An editor that joined the session at `addr`, with `query` added to the URL
+    async fn join(addr: SocketAddr, query: &str) -> Hub {
+        let guest = Hub::start(headless_editor(None).expect("Failed to create editor"));
+        client::join(&format!("ws://{}/ws?{}", addr, query), guest.clone(), None)
+            .await
+            .expect("Failed to join");
+        guest
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    // A protocol level connection, as `client`, resuming from `revision` if given
+    async fn connect(addr: SocketAddr, client: &str, revision: Option<u64>) -> Socket {
+        let mut url = format!("ws://{}/ws?client={}", addr, client);
+        if let Some(revision) = revision {
+            url.push_str(&format!("&revision={}", revision));
+        }
+        tokio_tungstenite::connect_async(url).await.expect("Failed to connect").0
+    }
+
+    // The next message, or None once the connection is closed
+    async fn next_message(ws: &mut Socket) -> Option<WsMessage> {
+        use futures_util::StreamExt;
+        loop {
+            match ws.next().await? {
+                Ok(tungstenite::Message::Text(text)) => return Some(serde_json::from_str(&text).expect("Invalid message")),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // The next message of the kind `matches` picks, skipping presence and the like
+    async fn next_matching(ws: &mut Socket, matches: impl Fn(&WsMessage) -> bool) -> WsMessage {
+        use futures_util::StreamExt;
+        loop {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(2), ws.next()).await.unwrap().unwrap().unwrap();
+            if let tungstenite::Message::Text(text) = message {
+                let message: WsMessage = serde_json::from_str(&text).unwrap();
+                if matches(&message) {
+                    return message;
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_index_to_line_column() {
         let text = "Hello\nWorld\nTest";
@@ -59,7 +172,8 @@ mod tests {
         let text = "Hello 🦀\nRust";
         assert_eq!(index_to_line_column(text, 0), Some((0, 0)));
         assert_eq!(index_to_line_column(text, 6), Some((0, 6))); // Before emoji
-        assert_eq!(index_to_line_column(text, 10), Some((0, 7))); // After emoji
+        assert_eq!(index_to_line_column(text, 8), None); // Inside emoji
+        assert_eq!(index_to_line_column(text, 10), Some((0, 10))); // After emoji, in bytes
         assert_eq!(index_to_line_column(text, 11), Some((1, 0))); // Second line
     }
 
@@ -79,6 +193,49 @@ mod tests {
         assert_eq!(Key::Up, Key::Up);
     }
 
+    // Raw terminal input that arrives in bursts, with a timed out read between them
+    struct Bursts(Vec<&'static [u8]>);
+
+    impl io::Read for Bursts {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(burst) = self.0.first_mut() else { return Ok(0) };
+            let n = burst.len().min(buf.len());
+            buf[..n].copy_from_slice(&burst[..n]);
+            *burst = &burst[n..];
+            if burst.is_empty() {
+                self.0.remove(0);
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_lone_escape_does_not_wait_for_more_keys() {
+        let mut input = Bursts(vec![b"\x1b", b"", b"ab", b"\x1b[A"]);
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Escape);
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Char('a'));
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Char('b'));
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Up);
+    }
+
+    #[test]
+    fn test_utf8_lead_byte_at_end_of_input_is_a_key() {
+        let mut input = Bursts(vec![b"\xc3"]);
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Ctrl(0xc3));
+        assert!(Key::decode(&mut input).is_err());
+
+        let mut input = Bursts(vec![b"\xc3", b"", b"\xa9"]);
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Ctrl(0xc3));
+    }
+
+    #[test]
+    fn test_invalid_utf8_lead_byte_is_a_key() {
+        let mut input = Bursts(vec![b"\xffa"]);
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Ctrl(0xff));
+        assert_eq!(Key::decode(&mut input).unwrap(), Key::Char('a'));
+        assert!(Key::decode(&mut input).is_err());
+    }
+
     #[test]
     fn test_text_change_serialization() {
         let change = TextChange {
@@ -100,12 +257,12 @@ mod tests {
         use std::io;
 
         fn create_test_editor() -> io::Result<Editor> {
-            Editor::new(None)
+            headless_editor(None)
         }
 
         fn create_test_editor_with_content(content: &str) -> io::Result<Editor> {
             let file = create_test_file(content);
-            Editor::new(Some(file.path().to_string_lossy().to_string()))
+            headless_editor(Some(file.path().to_string_lossy().to_string()))
         }
 
         #[test]
@@ -115,7 +272,7 @@ mod tests {
             assert_eq!(editor.content[0], "");
             assert_eq!(editor.cursor_x, 0);
             assert_eq!(editor.cursor_y, 0);
-            assert_eq!(editor.modified, false);
+            assert!(!editor.modified);
         }
 
         #[test]
@@ -129,7 +286,7 @@ mod tests {
             assert_eq!(editor.content[2], "Line 3");
             assert_eq!(editor.cursor_x, 0);
             assert_eq!(editor.cursor_y, 0);
-            assert_eq!(editor.modified, false);
+            assert!(!editor.modified);
         }
 
         #[test]
@@ -141,7 +298,7 @@ mod tests {
             assert!(result.is_ok());
             assert_eq!(editor.content[0], "H");
             assert_eq!(editor.cursor_x, 1);
-            assert_eq!(editor.modified, true);
+            assert!(editor.modified);
             
             // Insert second character
             let result = editor.insert_char('i');
@@ -163,7 +320,7 @@ mod tests {
             assert_eq!(editor.content[1], " World");
             assert_eq!(editor.cursor_x, 0);
             assert_eq!(editor.cursor_y, 1);
-            assert_eq!(editor.modified, true);
+            assert!(editor.modified);
         }
 
         #[test]
@@ -176,7 +333,7 @@ mod tests {
             
             assert_eq!(editor.content[0], "Helo");
             assert_eq!(editor.cursor_x, 2);
-            assert_eq!(editor.modified, true);
+            assert!(editor.modified);
         }
 
         #[test]
@@ -192,7 +349,7 @@ mod tests {
             assert_eq!(editor.content[0], "Line 1Line 2");
             assert_eq!(editor.cursor_x, 6);
             assert_eq!(editor.cursor_y, 0);
-            assert_eq!(editor.modified, true);
+            assert!(editor.modified);
         }
 
         #[test]
@@ -205,7 +362,7 @@ mod tests {
             
             assert_eq!(editor.content[0], "Helo");
             assert_eq!(editor.cursor_x, 2);
-            assert_eq!(editor.modified, true);
+            assert!(editor.modified);
         }
 
         #[test]
@@ -297,13 +454,13 @@ mod tests {
             let temp_file = NamedTempFile::new().expect("Failed to create temp file");
             let filename = temp_file.path().to_string_lossy().to_string();
             
-            let mut editor = Editor::new(Some(filename.clone())).expect("Failed to create editor");
+            let mut editor = headless_editor(Some(filename.clone())).expect("Failed to create editor");
             editor.content = vec!["Hello".to_string(), "World".to_string()];
             editor.modified = true;
             
             let result = editor.save_file();
             assert!(result.is_ok());
-            assert_eq!(editor.modified, false);
+            assert!(!editor.modified);
             
             // Verify file content
             let saved_content = fs::read_to_string(&filename).expect("Failed to read saved file");
@@ -316,7 +473,8 @@ mod tests {
             
             // Test character insertion
             let key = Key::Char('H');
-            let result = editor.broadcast_change(&key, 1).await;
+            editor.process_keypress(&key).unwrap();
+            let result = editor.broadcast_change(&key, 1);
             
             // Should not fail even without WebSocket connection
             assert!(result.is_ok());
@@ -328,7 +486,8 @@ mod tests {
             
             // Test newline insertion
             let key = Key::Enter;
-            let result = editor.broadcast_change(&key, 0).await;
+            editor.process_keypress(&key).unwrap();
+            let result = editor.broadcast_change(&key, 1);
             
             // Should not fail even without WebSocket connection
             assert!(result.is_ok());
@@ -339,8 +498,11 @@ mod tests {
             let mut editor = create_test_editor().expect("Failed to create editor");
             
             // Test backspace
+            editor.process_keypress(&Key::Char('é')).unwrap();
+            editor.broadcast_change(&Key::Char('é'), 2).unwrap();
             let key = Key::Backspace;
-            let result = editor.broadcast_change(&key, 1).await;
+            editor.process_keypress(&key).unwrap();
+            let result = editor.broadcast_change(&key, 0);
             
             // Should not fail even without WebSocket connection
             assert!(result.is_ok());
@@ -350,23 +512,21 @@ mod tests {
     mod integration_tests {
         use super::*;
         use tokio::time::{sleep, Duration};
-        use std::sync::Arc;
-        use parking_lot::Mutex;
 
         #[tokio::test]
         async fn test_websocket_server_setup() {
-            let editor = Arc::new(Mutex::new(Editor::new(None).expect("Failed to create editor")));
+            let editor = headless_editor(None).expect("Failed to create editor");
             
             // This test verifies that the WebSocket server can be set up
             // In a real scenario, we would test the actual WebSocket connection
             // but that requires more complex setup with a test client
             
-            let buffer_for_ws = editor.clone();
+            let hub = Hub::start(editor);
             let ws_route = warp::path("ws")
                 .and(warp::ws())
-                .and(warp::any().map(move || buffer_for_ws.clone()))
-                .map(|ws: warp::ws::Ws, buffer| {
-                    ws.on_upgrade(move |socket| handle_connection(socket, buffer))
+                .and(warp::any().map(move || hub.clone()))
+                .map(|ws: warp::ws::Ws, hub| {
+                    ws.on_upgrade(move |socket| handle_connection(socket, hub, ConnectParams::default()))
                 });
 
             // Start server in background
@@ -407,29 +567,2574 @@ mod tests {
         }
     }
 
-    mod terminal_tests {
+    mod cli_tests {
         use super::*;
-        
-        // Note: Terminal tests are limited because they require actual terminal interaction
-        // In a production environment, you might use a mock terminal or test framework
-        
+        use clap::Parser;
+        use cli::{Cli, LogLevel, LogRotation};
+        use text_editor::limits::Limits;
+        use std::net::{IpAddr, Ipv4Addr};
+
         #[test]
-        fn test_terminal_size_fallback() {
-            // Test that we handle terminal size detection gracefully
-            // This should not panic even if terminal commands fail
-            let result = Terminal::get_terminal_size();
-            
-            // Should either succeed or use fallback values
-            match result {
-                Ok((rows, cols)) => {
-                    assert!(rows > 0);
-                    assert!(cols > 0);
+        fn test_cli_defaults() {
+            let cli = Cli::try_parse_from(["text_editor"]).expect("Failed to parse");
+            assert_eq!(cli.filename, None);
+            assert_eq!(cli.host.port, 3030);
+            assert_eq!(cli.host.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+            assert!(!cli.no_server);
+            assert!(!cli.host.read_only);
+            assert_eq!(cli.host.log_level, LogLevel::Info);
+            assert_eq!(cli.host.log_file, None);
+            assert_eq!(cli.host.log_rotation, None);
+        }
+
+        #[test]
+        fn test_cli_log_options() {
+            let cli = Cli::try_parse_from(["text_editor", "--log-file", "edit.log", "--log-rotation", "hourly"]).expect("Failed to parse");
+            assert_eq!(cli.host.log_file.as_deref(), Some(std::path::Path::new("edit.log")));
+            assert_eq!(cli.host.log_rotation, Some(LogRotation::Hourly));
+
+            let Some(cli::Command::Serve(args)) = Cli::try_parse_from(["text_editor", "serve", "notes.txt"]).expect("Failed to parse").command else {
+                panic!("Expected the serve command");
+            };
+            assert_eq!(args.host.log_file, None);
+            assert_eq!(args.host.log_rotation, None);
+        }
+
+        #[test]
+        fn test_cli_options() {
+            let cli = Cli::try_parse_from([
+                "text_editor", "notes.txt", "--port", "0", "--bind", "0.0.0.0", "--read-only", "--log-level", "warn",
+            ])
+            .expect("Failed to parse");
+            assert_eq!(cli.filename.as_deref(), Some("notes.txt"));
+            assert_eq!(cli.host.port, 0);
+            assert_eq!(cli.host.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+            assert!(cli.host.read_only);
+            assert_eq!(cli.host.log_level, LogLevel::Warn);
+        }
+
+        #[test]
+        fn test_editor_and_serve_share_host_options() {
+            let options = ["--read-only", "--backup", "--autosave", "5", "--no-swap", "--edit-token", "s3cret"];
+            let editor_cli = Cli::try_parse_from(["text_editor", "notes.txt"].into_iter().chain(options)).expect("Failed to parse");
+            let serve_cli = Cli::try_parse_from(["text_editor", "serve", "notes.txt"].into_iter().chain(options)).expect("Failed to parse");
+            let Some(cli::Command::Serve(serve)) = serve_cli.command else {
+                panic!("Expected the serve command");
+            };
+            for host in [&editor_cli.host, &serve.host] {
+                let mut editor = headless_editor(None).unwrap();
+                host.configure(&mut editor).unwrap();
+                assert!(editor.read_only);
+                assert!(editor.backup);
+                assert!(!editor.swap);
+                assert_eq!(editor.autosave, Some(std::time::Duration::from_secs(5)));
+                assert_eq!(editor.edit_token.as_deref(), Some("s3cret"));
+            }
+        }
+
+        #[test]
+        fn test_cli_limits() {
+            let cli = Cli::try_parse_from(["text_editor"]).expect("Failed to parse");
+            assert_eq!(cli.host.limits(), Limits::default());
+
+            let args = ["text_editor", "serve", "notes.txt", "--max-message-size", "4096", "--max-document-size", "65536", "--rate-limit", "5"];
+            let Some(cli::Command::Serve(args)) = Cli::try_parse_from(args).expect("Failed to parse").command else {
+                panic!("Expected the serve command");
+            };
+            assert_eq!(args.host.limits(), Limits { max_message_size: 4096, max_document_size: 65536, messages_per_second: 5 });
+
+            let mut editor = headless_editor(None).unwrap();
+            args.host.configure(&mut editor).unwrap();
+            assert_eq!(editor.limits, args.host.limits());
+            assert!(Cli::try_parse_from(["text_editor", "--rate-limit", "0"]).is_err());
+        }
+
+        #[test]
+        fn test_cli_rejects_invalid_combinations() {
+            assert!(Cli::try_parse_from(["text_editor", "--tls-cert", "cert.pem"]).is_err());
+            assert!(Cli::try_parse_from(["text_editor", "--no-server", "--tls-self-signed"]).is_err());
+            assert!(Cli::try_parse_from(["text_editor", "--port", "70000"]).is_err());
+            assert!(Cli::try_parse_from(["text_editor", "--join", "ws://host/ws", "--edit-token", "s3cret"]).is_err());
+            assert!(Cli::try_parse_from(["text_editor", "--join", "ws://host/ws", "--op-log", "ops.jsonl"]).is_err());
+        }
+
+        #[tokio::test]
+        async fn test_port_zero_picks_free_port() {
+            let editor = Hub::start(headless_editor(None).expect("Failed to create editor"));
+            let (addr, _server) = bind_server(editor, ([127, 0, 0, 1], 0).into(), None, futures::future::pending())
+                .expect("Failed to bind server");
+            assert_ne!(addr.port(), 0);
+        }
+
+        #[tokio::test]
+        async fn test_port_conflict_is_an_error() {
+            let editor = Hub::start(headless_editor(None).expect("Failed to create editor"));
+            let (addr, server) = bind_server(editor.clone(), ([127, 0, 0, 1], 0).into(), None, futures::future::pending())
+                .expect("Failed to bind server");
+            let server = tokio::spawn(server);
+
+            let result = bind_server(editor, addr, None, futures::future::pending());
+            assert!(result.is_err());
+            server.abort();
+        }
+
+        #[test]
+        fn test_read_only_rejects_edits() {
+            let mut editor = headless_editor(None).expect("Failed to create editor");
+            editor.read_only = true;
+
+            let changed = editor.process_keypress(&Key::Char('x')).expect("Failed to process key");
+            assert!(!changed);
+            assert_eq!(editor.content[0], "");
+            assert!(!editor.modified);
+        }
+    }
+
+    mod tls_tests {
+        use super::*;
+        use std::path::Path;
+        use futures_util::SinkExt;
+        use tokio::time::{sleep, Duration};
+        use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+
+        #[tokio::test]
+        async fn test_wss_round_trip_with_self_signed_cert() {
+            let tls = tls::TlsConfig::self_signed(&["localhost".to_string()]).expect("Failed to generate certificate");
+            let editor = Hub::start(headless_editor(None).expect("Failed to create editor"));
+
+            let (addr, server) = bind_server(editor.clone(), ([127, 0, 0, 1], 0).into(), Some(tls.clone()), futures::future::pending())
+                .expect("Failed to bind server");
+            let server = tokio::spawn(server);
+
+            let connector = Connector::Rustls(Arc::new(tls::client_config(&tls.cert_pem).unwrap()));
+            let url = format!("wss://localhost:{}/ws", addr.port());
+            let (mut client, _) = connect_async_tls_with_config(url, None, false, Some(connector))
+                .await
+                .expect("TLS handshake failed");
+
+            let change = TextChange {
+                text: "Hi".to_string(),
+                start: 0,
+                end: 0,
+            };
+            client
+                .send(tungstenite::Message::Text(serde_json::to_string(&change).unwrap()))
+                .await
+                .expect("Failed to send change");
+            sleep(Duration::from_millis(100)).await;
+
+            assert_eq!(editor.with(|editor| editor.content[0].clone()).await, "Hi");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_wss_rejects_untrusted_certificate() {
+            let tls = tls::TlsConfig::self_signed(&["localhost".to_string()]).expect("Failed to generate certificate");
+            let editor = Hub::start(headless_editor(None).expect("Failed to create editor"));
+
+            let (addr, server) = bind_server(editor, ([127, 0, 0, 1], 0).into(), Some(tls), futures::future::pending())
+                .expect("Failed to bind server");
+            let server = tokio::spawn(server);
+
+            // A different self-signed certificate is not a valid root for the server's
+            let other = tls::TlsConfig::self_signed(&["localhost".to_string()]).expect("Failed to generate certificate");
+            let connector = Connector::Rustls(Arc::new(tls::client_config(&other.cert_pem).unwrap()));
+            let url = format!("wss://localhost:{}/ws", addr.port());
+            let result = connect_async_tls_with_config(url, None, false, Some(connector)).await;
+
+            assert!(result.is_err());
+            server.abort();
+        }
+
+        #[test]
+        fn test_tls_config_missing_files() {
+            let result = tls::TlsConfig::from_files(Path::new("/nonexistent/cert.pem"), Path::new("/nonexistent/key.pem"));
+            assert!(result.is_err());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_write_cert_replaces_rather_than_follows() {
+            use std::os::unix::fs::PermissionsExt;
+            let dir = tempfile::tempdir().unwrap();
+            let target = dir.path().join("victim");
+            fs::write(&target, "untouched").unwrap();
+            let path = dir.path().join("state/dev-cert-3030.pem");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::os::unix::fs::symlink(&target, &path).unwrap();
+
+            let tls = tls::TlsConfig::self_signed(&["localhost".to_string()]).unwrap();
+            tls.write_cert(&path).unwrap();
+            assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+            assert!(!fs::symlink_metadata(&path).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read(&path).unwrap(), tls.cert_pem);
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+            // Starting again on the same port writes the new certificate
+            let other = tls::TlsConfig::self_signed(&["localhost".to_string()]).unwrap();
+            other.write_cert(&path).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), other.cert_pem);
+        }
+    }
+
+    mod ot_tests {
+        use super::*;
+        use ot::Operation;
+
+        fn op(doc: &str, changes: &[TextChange]) -> Operation {
+            Operation::from_changes(doc.len(), changes).expect("Failed to build operation")
+        }
+
+        fn assert_converges(doc: &str, a: &Operation, b: &Operation) -> String {
+            let (a_prime, b_prime) = Operation::transform(a, b).expect("Failed to transform");
+            let left = b_prime.apply(&a.apply(doc).unwrap()).unwrap();
+            let right = a_prime.apply(&b.apply(doc).unwrap()).unwrap();
+            assert_eq!(left, right);
+            left
+        }
+
+        #[test]
+        fn test_apply_replace() {
+            let doc = "Hello World";
+            assert_eq!(op(doc, &[change("Rust", 6, 11)]).apply(doc).unwrap(), "Hello Rust");
+            assert_eq!(op(doc, &[change("", 0, 6)]).apply(doc).unwrap(), "World");
+            assert_eq!(op(doc, &[change("!", 11, 11)]).apply(doc).unwrap(), "Hello World!");
+        }
+
+        #[test]
+        fn test_from_changes_rejects_invalid_ranges() {
+            assert!(Operation::from_changes(5, &[change("x", 3, 2)]).is_err());
+            assert!(Operation::from_changes(5, &[change("x", 4, 6)]).is_err());
+            assert!(Operation::from_changes(5, &[change("", 0, 3), change("", 2, 4)]).is_err());
+        }
+
+        #[test]
+        fn test_apply_rejects_wrong_length_and_split_characters() {
+            let doc = "Hi 🦀";
+            assert!(op("Hi", &[change("x", 0, 0)]).apply(doc).is_err());
+            assert!(op(doc, &[change("x", 4, 4)]).apply(doc).is_err());
+        }
+
+        #[test]
+        fn test_to_changes_round_trip() {
+            let doc = "one two three";
+            let changes = vec![change("1", 0, 3), change("", 4, 8), change("!", 13, 13)];
+            let operation = op(doc, &changes);
+            assert_eq!(operation.to_changes(), changes);
+            assert_eq!(op(doc, &operation.to_changes()), operation);
+        }
+
+        #[test]
+        fn test_transform_concurrent_inserts() {
+            let doc = "abc";
+            let result = assert_converges(doc, &op(doc, &[change("X", 1, 1)]), &op(doc, &[change("Y", 1, 1)]));
+            assert_eq!(result, "aXYbc");
+        }
+
+        #[test]
+        fn test_transform_insert_inside_deletion() {
+            let doc = "0123456789";
+            let result = assert_converges(doc, &op(doc, &[change("", 2, 8)]), &op(doc, &[change("X", 5, 5)]));
+            assert_eq!(result, "01X89");
+        }
+
+        #[test]
+        fn test_transform_overlapping_replacements() {
+            let doc = "Hello World";
+            assert_converges(doc, &op(doc, &[change("Bye", 0, 7)]), &op(doc, &[change("Earth", 6, 11)]));
+            assert_converges(doc, &op(doc, &[change("", 0, 11)]), &op(doc, &[change("", 3, 5), change("?", 11, 11)]));
+        }
+
+        #[test]
+        fn test_transform_index() {
+            let doc = "Hello World";
+            assert_eq!(op(doc, &[change("Big ", 6, 6)]).transform_index(8), 12);
+            assert_eq!(op(doc, &[change("", 0, 6)]).transform_index(8), 2);
+            assert_eq!(op(doc, &[change("", 0, 6)]).transform_index(3), 0);
+            assert_eq!(op(doc, &[change("!", 11, 11)]).transform_index(3), 3);
+        }
+    }
+
+    mod join_tests {
+        use super::*;
+        use tokio::time::{sleep, Duration};
+
+        async fn host_and_guest(content: &str) -> (Hub, Hub, JoinHandle<()>) {
+            let (host, addr, server) = host(content);
+            (host, join(addr, "").await, server)
+        }
+
+        async fn type_keys(editor: &Hub, keys: &[Key]) {
+            let keys = keys.to_vec();
+            editor
+                .with(move |editor| {
+                    for key in &keys {
+                        if editor.process_keypress(key).unwrap() {
+                            let start = editor.cursor_offset();
+                            editor.broadcast_change(key, start).unwrap();
+                        }
+                    }
+                })
+                .await;
+        }
+
+        #[tokio::test]
+        async fn test_join_receives_snapshot() {
+            let (_host, guest, server) = host_and_guest("Hello\nWorld").await;
+            assert_eq!(guest.with(|guest| guest.content.clone()).await, vec!["Hello", "World"]);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_join_edits_flow_both_ways() {
+            let (host, guest, server) = host_and_guest("Hello").await;
+
+            guest.with(|guest| guest.cursor_x = 5).await;
+            type_keys(&guest, &[Key::Char('!')]).await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(|host| host.content.clone()).await, vec!["Hello!"]);
+
+            type_keys(&host, &[Key::Delete, Key::Enter]).await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(|host| host.content.clone()).await, vec!["", "ello!"]);
+            assert_eq!(guest.with(|guest| guest.content.clone()).await, vec!["", "ello!"]);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_join_concurrent_edits_converge() {
+            let (host, guest, server) = host_and_guest("abc\ndef").await;
+
+            // Both sides type before hearing from each other
+            guest.with(|guest| guest.cursor_y = 1).await;
+            type_keys(&guest, &[Key::Char('x'), Key::Char('y'), Key::Backspace, Key::Enter]).await;
+            type_keys(&host, &[Key::End, Key::Char('1'), Key::Char('2'), Key::Down, Key::Backspace]).await;
+            sleep(Duration::from_millis(200)).await;
+
+            assert_eq!(host.with(|host| host.text()).await, guest.with(|guest| guest.text()).await);
+            assert_eq!(host.with(|host| host.text()).await, "abc12\nx\nde");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_guest_save_request() {
+            let (host, guest, server) = host_and_guest("Hello").await;
+
+            // Nowhere to save to yet
+            guest.with(|guest| guest.process_keypress(&Key::Ctrl(b's')).unwrap()).await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(guest.with(|guest| guest.status_message.clone()).await.contains("no file name"));
+
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shared.txt");
+            let filename = path.to_string_lossy().to_string();
+            host.with(|host| host.filename = Some(filename)).await;
+            type_keys(&guest, &[Key::Char('>')]).await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(guest.with(|guest| guest.modified).await);
+
+            guest.with(|guest| guest.process_keypress(&Key::Ctrl(b's')).unwrap()).await;
+            // The result reaches the guest a little after the file is written
+            for _ in 0..20 {
+                sleep(Duration::from_millis(50)).await;
+                if !guest.with(|guest| guest.modified).await {
+                    break;
                 }
-                Err(_) => {
-                    // Terminal commands may fail in test environment
-                    // This is acceptable behavior
+            }
+            assert_eq!(fs::read_to_string(&path).unwrap(), ">Hello");
+            assert!(!host.with(|host| host.modified).await);
+            assert!(!guest.with(|guest| guest.modified).await);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_host_save_marks_guests_clean() {
+            let (host, guest, server) = host_and_guest("Hello").await;
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shared.txt");
+            let filename = path.to_string_lossy().to_string();
+            host.with(|host| host.filename = Some(filename)).await;
+
+            type_keys(&host, &[Key::Char('1')]).await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(guest.with(|guest| guest.modified).await);
+
+            host.with(|host| host.process_keypress(&Key::Ctrl(b's')).unwrap()).await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(!guest.with(|guest| guest.modified).await);
+            assert!(guest.with(|guest| guest.status_message.clone()).await.starts_with("Saved by host"));
+            server.abort();
+        }
+    }
+
+    mod e2e_tests {
+        use super::*;
+        use futures_util::SinkExt;
+        use session::GuestSession;
+        use tokio::time::{timeout, Duration};
+        use tungstenite::Message;
+
+        // How long the host has to be silent before a replica is considered caught up
+        const QUIET: Duration = Duration::from_millis(100);
+
+        // A client speaking the protocol over its own socket and keeping its
+        // own copy of the document, like the VS Code extension
+        struct Replica {
+            client: String,
+            ws: Option<Socket>,
+            text: String,
+            session: GuestSession,
+            outgoing: mpsc::UnboundedReceiver<WsMessage>,
+        }
+
+        impl Replica {
+            async fn connect(addr: SocketAddr, client: &str) -> Replica {
+                let mut ws = connect(addr, client, None).await;
+                let Some(WsMessage::Snapshot { text, revision }) = next_message(&mut ws).await else {
+                    panic!("Expected a snapshot");
+                };
+                let (tx, outgoing) = mpsc::unbounded_channel();
+                Replica { client: client.to_string(), ws: Some(ws), text, session: GuestSession::new(revision, tx), outgoing }
+            }
+
+            async fn edit(&mut self, change: TextChange) {
+                let op = Operation::from_changes(self.text.len(), &[change]).unwrap();
+                self.text = op.apply(&self.text).unwrap();
+                self.session.local(op);
+                self.flush().await;
+            }
+
+            // Send what the session queued, unless offline
+            async fn flush(&mut self) {
+                while let Ok(message) = self.outgoing.try_recv() {
+                    if let Some(ws) = &mut self.ws {
+                        ws.send(Message::Text(serde_json::to_string(&message).unwrap())).await.unwrap();
+                    }
+                }
+            }
+
+            fn receive(&mut self, message: WsMessage) {
+                match message {
+                    WsMessage::Snapshot { text, revision } => {
+                        self.session.reset(revision);
+                        self.text = text;
+                    }
+                    WsMessage::Change { revision, changes } => {
+                        let op = Operation::from_changes(self.session.host_len(self.text.len()), &changes).unwrap();
+                        let op = self.session.remote(revision, op).unwrap();
+                        self.text = op.apply(&self.text).unwrap();
+                    }
+                    WsMessage::Ack { revision } => self.session.ack(revision),
+                    WsMessage::Resumed { revision } => self.session.resumed(revision),
+                    _ => {}
                 }
             }
+
+            // Handle what the host sent until it goes quiet. Returns whether
+            // anything arrived.
+            async fn poll(&mut self) -> bool {
+                let mut received = false;
+                while let Some(ws) = &mut self.ws {
+                    let Ok(Some(message)) = timeout(QUIET, next_message(ws)).await else {
+                        break;
+                    };
+                    self.receive(message);
+                    self.flush().await;
+                    received = true;
+                }
+                received
+            }
+
+            fn disconnect(&mut self) {
+                self.ws = None;
+                self.session.disconnected();
+            }
+
+            // Come back asking for what was missed since the last revision seen
+            async fn reconnect(&mut self, addr: SocketAddr) {
+                while self.outgoing.try_recv().is_ok() {}
+                self.ws = Some(connect(addr, &self.client, Some(self.session.revision())).await);
+            }
+        }
+
+        // Deterministic pseudo-random edits
+        struct Rng(u64);
+
+        impl Rng {
+            fn below(&mut self, n: usize) -> usize {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                (self.0 % n.max(1) as u64) as usize
+            }
+
+            fn change(&mut self, text: &str, tag: char) -> TextChange {
+                let start = self.below(text.len() + 1);
+                if self.below(3) == 0 && start < text.len() {
+                    let end = (start + 1 + self.below(3)).min(text.len());
+                    TextChange { text: String::new(), start, end }
+                } else {
+                    TextChange { text: tag.to_string().repeat(1 + self.below(2)), start, end: start }
+                }
+            }
+        }
+
+        async fn host_types(host: &Hub, c: char) {
+            host.with(move |host| {
+                if host.process_keypress(&Key::Char(c)).unwrap() {
+                    let start = host.cursor_offset();
+                    host.broadcast_change(&Key::Char(c), start).unwrap();
+                }
+            })
+            .await;
+        }
+
+        async fn settle(replicas: &mut [Replica]) {
+            loop {
+                let mut received = false;
+                for replica in replicas.iter_mut() {
+                    received |= replica.poll().await;
+                }
+                if !received {
+                    break;
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn test_clients_converge_with_interleaved_changes() {
+            let (host, addr, server) = host("The quick brown fox");
+            let mut replicas = Vec::new();
+            for client in ["a", "b", "c"] {
+                replicas.push(Replica::connect(addr, client).await);
+            }
+
+            // Nobody waits for the others before making the next change
+            let mut rng = Rng(0x2545F4914F6CDD1D);
+            for round in 0..25 {
+                for (replica, tag) in replicas.iter_mut().zip(['a', 'b', 'c']) {
+                    let change = rng.change(&replica.text, tag);
+                    replica.edit(change).await;
+                }
+                if round % 5 == 0 {
+                    host_types(&host, 'H').await;
+                }
+                if round % 3 == 0 {
+                    let replica = &mut replicas[rng.below(3)];
+                    replica.poll().await;
+                }
+            }
+            settle(&mut replicas).await;
+
+            let text = host.with(|host| host.text()).await;
+            for replica in &replicas {
+                assert_eq!(replica.text, text, "Replica {} diverged", replica.client);
+                assert!(!replica.session.has_pending());
+            }
+            for tag in ['a', 'b', 'c', 'H'] {
+                assert!(text.contains(tag), "Lost the changes tagged {}: {:?}", tag, text);
+            }
+
+            // Someone joining late starts from the same text
+            let late = Replica::connect(addr, "d").await;
+            assert_eq!(late.text, text);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_deleting_a_multibyte_character_keeps_concurrent_edits() {
+            let (host, addr, server) = host("café crème");
+            let mut replica = Replica::connect(addr, "a").await;
+
+            // Made before the replica hears of the deletions
+            replica.edit(TextChange { text: "!".to_string(), start: 12, end: 12 }).await;
+            host.with(|host| {
+                host.cursor_x = 5;
+                for (key, cursor_x) in [(Key::Backspace, None), (Key::Delete, Some(6))] {
+                    if let Some(x) = cursor_x {
+                        host.cursor_x = x;
+                    }
+                    assert!(host.process_keypress(&key).unwrap());
+                    let start = host.cursor_offset();
+                    host.broadcast_change(&key, start).unwrap();
+                }
+            })
+            .await;
+            settle(std::slice::from_mut(&mut replica)).await;
+
+            assert_eq!(host.with(|host| host.text()).await, "caf crme!");
+            assert_eq!(replica.text, "caf crme!");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_client_catches_up_after_reconnecting() {
+            let (host, addr, server) = host("shared");
+            let mut away = Replica::connect(addr, "away").await;
+            let mut stays = Replica::connect(addr, "stays").await;
+
+            // Gone before hearing back about its change, which may or may not have arrived
+            away.edit(TextChange { text: "1".to_string(), start: 0, end: 0 }).await;
+            away.disconnect();
+
+            for n in 0..3 {
+                let end = stays.text.len();
+                stays.edit(TextChange { text: n.to_string(), start: end, end }).await;
+                stays.poll().await;
+            }
+            host_types(&host, '>').await;
+            // More changes made while offline go out once reconnected
+            away.edit(TextChange { text: "2".to_string(), start: 1, end: 1 }).await;
+
+            away.reconnect(addr).await;
+            let mut replicas = [away, stays];
+            settle(&mut replicas).await;
+
+            let text = host.with(|host| host.text()).await;
+            assert_eq!(replicas[0].text, text);
+            assert_eq!(replicas[1].text, text);
+            assert!(text.ends_with("012"));
+            // Once from each replica: nothing lost offline, nothing applied twice
+            assert_eq!(text.matches('1').count(), 2, "{:?}", text);
+            assert_eq!(text.matches('2').count(), 2, "{:?}", text);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_dropped_client_does_not_disturb_the_others() {
+            let (host, addr, server) = host("");
+            let mut gone = Replica::connect(addr, "gone").await;
+            let mut stays = Replica::connect(addr, "stays").await;
+            gone.edit(TextChange { text: "bye".to_string(), start: 0, end: 0 }).await;
+            gone.poll().await;
+            drop(gone);
+
+            stays.poll().await;
+            stays.edit(TextChange { text: "!".to_string(), start: 3, end: 3 }).await;
+            stays.poll().await;
+
+            assert_eq!(host.with(|host| host.text()).await, "bye!");
+            assert_eq!(host.with(|host| host.status_message.clone()).await, "peer 1 changed 1 byte");
+            assert_eq!(stays.text, "bye!");
+            let peers = host
+                .with(|host| match &host.session {
+                    Session::Host(session) => Some(session.peer_count()),
+                    Session::Guest(_) => None,
+                })
+                .await;
+            assert_eq!(peers, Some(1), "Expected to host");
+            server.abort();
+        }
+    }
+
+    mod presence_tests {
+        use super::*;
+        use tokio::time::{sleep, Duration};
+
+        async fn host_and_guest(content: &str, query: &str) -> (Hub, Hub, JoinHandle<()>) {
+            let (host, addr, server) = host(content);
+            host.with(|host| host.name = "alice".to_string()).await;
+            host.with(|host| host.edit_token = Some("secret".to_string())).await;
+            let guest = join(addr, query).await;
+            sleep(Duration::from_millis(100)).await;
+            (host, guest, server)
+        }
+
+        #[test]
+        fn test_roles_granted_by_token() {
+            let mut editor = headless_editor(None).unwrap();
+            editor.edit_token = Some("secret".to_string());
+            let (tx, mut rx) = outbox();
+            let params = |role, token: Option<&str>| ConnectParams { role, token: token.map(str::to_string), ..ConnectParams::default() };
+
+            let editor_peer = editor.add_peer(tx.clone(), params(None, Some("secret"))).unwrap();
+            let wrong_token = editor.add_peer(tx.clone(), params(Some(Role::Editor), Some("guess"))).unwrap();
+            let viewer = editor.add_peer(tx, params(Some(Role::Viewer), Some("secret"))).unwrap();
+            assert_eq!(editor.peer_role(editor_peer), Some(Role::Editor));
+            assert_eq!(editor.peer_role(wrong_token), Some(Role::Viewer));
+            assert_eq!(editor.peer_role(viewer), Some(Role::Viewer));
+
+            // Snapshot, role, then the host's cursor
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Snapshot { .. }));
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Joined { role: Role::Editor, id: editor_peer.to_string() });
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Presence { offset: 0, .. }));
+        }
+
+        #[tokio::test]
+        async fn test_presence_names_the_buffer() {
+            let dir = tempfile::tempdir().unwrap();
+            let (_, mut editor) = open(&dir, "one");
+            editor.name = "alice".to_string();
+            let other = dir.path().join("other.txt");
+            fs::write(&other, "two").unwrap();
+            let (host, addr, server) = serve(editor);
+            let guest = join(addr, "name=bob").await;
+            sleep(Duration::from_millis(100)).await;
+
+            let buffers = |editor: &Editor| editor.presence.iter().map(|(_, c)| (c.name.clone(), c.buffer.clone())).collect::<Vec<_>>();
+            assert_eq!(guest.with(move |guest| buffers(guest)).await, vec![("alice".to_string(), "notes.txt".to_string())]);
+
+            // The session moves to the file the host opens, and so does everyone in it
+            host.with(move |host| {
+                host.process_keypress(&Key::Ctrl(b'o')).unwrap();
+                answer(host, other.to_str().unwrap());
+            })
+            .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(guest.with(|guest| guest.text()).await, "two");
+            assert_eq!(guest.with(move |guest| buffers(guest)).await, vec![("alice".to_string(), "other.txt".to_string())]);
+            assert_eq!(host.with(move |host| buffers(host)).await, vec![("bob".to_string(), "other.txt".to_string())]);
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_viewer_cannot_edit() {
+            let (host, guest, server) = host_and_guest("Hello", "role=viewer&token=secret").await;
+            assert!(guest.with(|guest| guest.read_only).await);
+
+            // Typing is refused locally
+            guest.with(|guest| guest.process_keypress(&Key::Char('x')).unwrap()).await;
+            assert_eq!(guest.with(|guest| guest.text()).await, "Hello");
+
+            // and a change sent anyway is refused by the host
+            guest
+                .with(|guest| {
+                    if let Session::Guest(guest) = &mut guest.session {
+                        guest.local(Operation::from_changes(5, &[TextChange { text: "!".to_string(), start: 5, end: 5 }]).unwrap());
+                    }
+                })
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(|host| host.text()).await, "Hello");
+            assert!(guest.with(|guest| guest.status_message.clone()).await.contains("viewers cannot change the document"));
+
+            // Viewers still follow along
+            host.with(|host| {
+                host.cursor_x = 5;
+                host.process_keypress(&Key::Char('!')).unwrap();
+                host.broadcast_change(&Key::Char('!'), 6).unwrap();
+            })
+            .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(guest.with(|guest| guest.text()).await, "Hello!");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_cursors_follow_edits() {
+            let (host, guest, server) = host_and_guest("Hello\nWorld", "name=bob&token=secret").await;
+            assert!(!guest.with(|guest| guest.read_only).await);
+            let names = guest.with(|guest| guest.presence.iter().map(|(_, c)| c.name.clone()).collect::<Vec<_>>()).await;
+            assert_eq!(names, vec!["alice"]);
+
+            // Guest moves to "World", host types in front of it
+            guest.with(|guest| guest.process_keypress(&Key::Down).unwrap()).await;
+            sleep(Duration::from_millis(100)).await;
+            let bob = |host: &Editor| host.presence.iter().next().map(|(_, c)| (c.name.clone(), c.offset));
+            assert_eq!(host.with(move |host| bob(host)).await, Some(("bob".to_string(), 6)));
+
+            host.with(|host| {
+                host.process_keypress(&Key::Char('>')).unwrap();
+                host.broadcast_change(&Key::Char('>'), 1).unwrap();
+            })
+            .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(move |host| bob(host)).await, Some(("bob".to_string(), 7)));
+            let offsets = guest.with(|guest| guest.presence.iter().map(|(_, c)| c.offset).collect::<Vec<_>>()).await;
+            assert_eq!(offsets, vec![1]);
+            server.abort();
+        }
+    }
+
+    mod follow_tests {
+        use super::*;
+
+        fn host_with_peers(names: &[&str]) -> (Editor, Vec<PeerId>, mpsc::Receiver<WsMessage>) {
+            let mut editor = headless_editor(None).unwrap();
+            let lines: Vec<String> = (1..=200).map(|n| format!("line {}", n)).collect();
+            editor.set_text(&lines.join("\n"));
+            let (tx, rx) = outbox();
+            let peers = names
+                .iter()
+                .map(|name| editor.add_peer(tx.clone(), ConnectParams { name: Some(name.to_string()), ..ConnectParams::default() }).unwrap())
+                .collect();
+            (editor, peers, rx)
+        }
+
+        fn follow(editor: &mut Editor, name: &str) {
+            editor.process_keypress(&Key::Ctrl(b't')).unwrap();
+            for c in name.chars() {
+                editor.process_keypress(&Key::Char(c)).unwrap();
+            }
+            editor.process_keypress(&Key::Enter).unwrap();
+        }
+
+        fn line_offset(editor: &Editor, line: usize) -> usize {
+            editor.content[..line].iter().map(|l| l.len() + 1).sum()
+        }
+
+        #[test]
+        fn test_viewport_tracks_followed_cursor() {
+            let (mut editor, peers, _rx) = host_with_peers(&["alice", "bob"]);
+            editor.peer_cursor(peers[0], 0, line_offset(&editor, 150)).unwrap();
+            editor.peer_cursor(peers[1], 0, line_offset(&editor, 20)).unwrap();
+            assert_eq!(editor.offset_y, 0);
+
+            follow(&mut editor, "al");
+            assert_eq!(editor.following, Some(peers[0].to_string()));
+            assert!(editor.offset_y <= 150 && 150 < editor.offset_y + editor.terminal_rows);
+            assert_eq!(editor.cursor_y, 0);
+
+            // Moves with them, including when their text moves
+            editor.peer_cursor(peers[0], 0, line_offset(&editor, 190)).unwrap();
+            assert!(editor.offset_y <= 190 && 190 < editor.offset_y + editor.terminal_rows);
+            editor.peer_cursor(peers[0], 0, 5).unwrap();
+            assert_eq!(editor.offset_y, 0);
+
+            // Following someone else, then stopping with a navigation key
+            follow(&mut editor, "Bob");
+            assert_eq!(editor.following, Some(peers[1].to_string()));
+            editor.process_keypress(&Key::Down).unwrap();
+            assert_eq!(editor.following, None);
+            assert_eq!(editor.cursor_y, 1);
+        }
+
+        #[test]
+        fn test_follow_needs_someone_to_follow() {
+            let (mut editor, _, _rx) = host_with_peers(&[]);
+            editor.process_keypress(&Key::Ctrl(b't')).unwrap();
+            assert!(editor.prompt.is_none());
+
+            let (mut editor, peers, _rx) = host_with_peers(&["alice"]);
+            editor.peer_cursor(peers[0], 0, 0).unwrap();
+            follow(&mut editor, "zed");
+            assert_eq!(editor.following, None);
+
+            // With one other person an empty name picks them, until they leave
+            follow(&mut editor, "");
+            assert_eq!(editor.following, Some(peers[0].to_string()));
+            editor.remove_peer(peers[0]);
+            assert_eq!(editor.following, None);
+            assert!(editor.status_message.contains("alice left"));
+        }
+
+        #[test]
+        fn test_status_bar_names_the_followed_buffer() {
+            let terminal = Headless::new(24, 120);
+            let mut editor = Editor::with_terminal(None, Arc::new(terminal.clone())).unwrap();
+            editor.set_text("one\ntwo");
+            editor.presence.update("host", "alice", "notes.txt", 4);
+            follow(&mut editor, "");
+            editor.refresh_screen().unwrap();
+            assert!(terminal.last_frame().unwrap().contains("| following alice in notes.txt"));
+        }
+    }
+
+    mod comment_tests {
+        use super::*;
+        use tokio::time::{sleep, Duration};
+
+        fn lines_with_comments(editor: &Editor) -> Vec<(usize, String)> {
+            editor
+                .comments_by_line()
+                .into_iter()
+                .flat_map(|(line, comments)| comments.into_iter().map(move |c| (line, c.text.clone())))
+                .collect()
+        }
+
+        #[tokio::test]
+        async fn test_comment_moves_with_its_line() {
+            let mut editor = headless_editor(None).unwrap();
+            editor.name = "alice".to_string();
+            editor.set_text("one\ntwo\nthree");
+            let (tx, mut rx) = outbox();
+            editor.add_peer(tx, ConnectParams::default()).unwrap();
+            while rx.try_recv().is_ok() {}
+
+            editor.cursor_y = 1;
+            editor.cursor_x = 2;
+            editor.process_keypress(&Key::Ctrl(b'r')).unwrap();
+            answer(&mut editor, "rename this");
+            assert_eq!(lines_with_comments(&editor), vec![(1, "rename this".to_string())]);
+            let comment = WsMessage::Comment { id: 0, author: "alice".to_string(), offset: 4, text: "rename this".to_string() };
+            assert!(std::iter::from_fn(|| rx.try_recv().ok()).any(|m| m == comment));
+
+            // Lines added above push it down, edits on the line itself do not matter
+            editor.cursor_y = 0;
+            editor.cursor_x = 0;
+            editor.process_keypress(&Key::Enter).unwrap();
+            editor.broadcast_change(&Key::Enter, 1).unwrap();
+            assert_eq!(lines_with_comments(&editor), vec![(2, "rename this".to_string())]);
+
+            editor.cursor_y = 2;
+            editor.process_keypress(&Key::Ctrl(b'k')).unwrap();
+            assert!(lines_with_comments(&editor).is_empty());
+            assert!(std::iter::from_fn(|| rx.try_recv().ok()).any(|m| m == WsMessage::ResolveComment { id: 0 }));
+        }
+
+        #[tokio::test]
+        async fn test_chat_and_comments_between_editors() {
+            let (host, addr, server) = host("fn main() {\n    todo!()\n}");
+            host.with(|host| host.name = "alice".to_string()).await;
+
+            // A viewer may still talk and comment
+            let guest = join(addr, "name=bob&role=viewer").await;
+            sleep(Duration::from_millis(100)).await;
+
+            guest
+                .with(|guest| {
+                    guest.process_keypress(&Key::Ctrl(b'e')).unwrap();
+                    answer(guest, "look at line 2");
+                })
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(|host| host.status_message.clone()).await, "bob: look at line 2");
+            assert_eq!(guest.with(|guest| guest.status_message.clone()).await, "bob: look at line 2");
+
+            guest
+                .with(|guest| {
+                    guest.process_keypress(&Key::Down).unwrap();
+                    guest.process_keypress(&Key::Ctrl(b'r')).unwrap();
+                    answer(guest, "still a todo");
+                })
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(host.with(|host| lines_with_comments(host)).await, vec![(1, "still a todo".to_string())]);
+            assert_eq!(guest.with(|guest| lines_with_comments(guest)).await, vec![(1, "still a todo".to_string())]);
+
+            // The host inserts a line above, moving the comment on both sides
+            host.with(|host| {
+                host.cursor_x = 11;
+                host.process_keypress(&Key::Enter).unwrap();
+                host.broadcast_change(&Key::Enter, 12).unwrap();
+            })
+            .await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(guest.with(|guest| lines_with_comments(guest)).await, vec![(2, "still a todo".to_string())]);
+
+            host.with(|host| {
+                host.cursor_y = 2;
+                host.process_keypress(&Key::Ctrl(b'k')).unwrap();
+            })
+            .await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(guest.with(|guest| lines_with_comments(guest)).await.is_empty());
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_peer_text_cannot_reach_the_terminal_raw() {
+            let terminal = Headless::new(24, 80);
+            let mut editor = Editor::with_terminal(None, Arc::new(terminal.clone())).unwrap();
+            editor.set_text("one\ntwo");
+            let params = ConnectParams { name: Some("eve\x1b[2J".to_string()), ..ConnectParams::default() };
+            let (tx, mut rx) = outbox();
+            let peer = editor.add_peer(tx, params).unwrap();
+            while rx.try_recv().is_ok() {}
+
+            editor.peer_chat(peer, "hi\x1b]0;owned\x07\nthere");
+            assert_eq!(editor.status_message, "eve[2J: hi]0;owned there");
+            let chat = WsMessage::Chat { author: "eve[2J".to_string(), text: "hi]0;owned there".to_string() };
+            assert_eq!(rx.try_recv().unwrap(), chat);
+
+            editor.peer_comment(peer, 0, 4, "\x1b[31mred").unwrap();
+            assert_eq!(editor.comments_by_line()[&1][0].text, "[31mred");
+
+            // Nothing wraps past the status line
+            editor.peer_chat(peer, &"x".repeat(200));
+            editor.refresh_screen().unwrap();
+            let frame = terminal.last_frame().unwrap();
+            assert_eq!(frame.matches('\x1b').count(), frame.matches("\x1b[").count());
+            assert!(frame.ends_with(&format!("eve[2J: {}\x1b[1;1H", "x".repeat(72))), "{:?}", frame);
+        }
+
+        #[tokio::test]
+        async fn test_viewer_resolves_only_its_own_comments() {
+            use futures_util::SinkExt;
+            use tokio_tungstenite::connect_async;
+            use tungstenite::Message;
+
+            let (host, addr, server) = host("one\ntwo");
+            host.with(|host| {
+                host.process_keypress(&Key::Ctrl(b'r')).unwrap();
+                answer(host, "from the host");
+            })
+            .await;
+
+            let (mut ws, _) = connect_async(format!("ws://{}/ws?client=bob&role=viewer", addr)).await.unwrap();
+            let send = |message: WsMessage| Message::Text(serde_json::to_string(&message).unwrap());
+            ws.send(send(WsMessage::ResolveComment { id: 0 })).await.unwrap();
+            let WsMessage::Error { message } = next_matching(&mut ws, |m| matches!(m, WsMessage::Error { .. })).await else {
+                unreachable!()
+            };
+            assert!(message.contains("resolve"), "{}", message);
+            assert_eq!(host.with(|host| lines_with_comments(host)).await, vec![(0, "from the host".to_string())]);
+
+            ws.send(send(WsMessage::AddComment { revision: 0, offset: 4, text: "mine".to_string() })).await.unwrap();
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Comment { id: 1, .. })).await;
+            ws.send(send(WsMessage::ResolveComment { id: 1 })).await.unwrap();
+            next_matching(&mut ws, |m| *m == WsMessage::ResolveComment { id: 1 }).await;
+            assert_eq!(host.with(|host| lines_with_comments(host)).await, vec![(0, "from the host".to_string())]);
+            server.abort();
+        }
+    }
+
+    mod reconnect_tests {
+        use super::*;
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::task::AbortHandle;
+        use tokio::time::{sleep, Duration};
+
+        // Forwards TCP connections to `target` until cut, to simulate a network drop
+        struct Proxy {
+            addr: SocketAddr,
+            target: SocketAddr,
+            tasks: Arc<Mutex<Vec<AbortHandle>>>,
+        }
+
+        impl Proxy {
+            async fn start(addr: SocketAddr, target: SocketAddr) -> Proxy {
+                let listener = TcpListener::bind(addr).await.expect("Failed to bind proxy");
+                let addr = listener.local_addr().unwrap();
+                let tasks = Arc::new(Mutex::new(Vec::new()));
+                let conns = tasks.clone();
+                let accept = tokio::spawn(async move {
+                    while let Ok((mut client, _)) = listener.accept().await {
+                        let conn = tokio::spawn(async move {
+                            if let Ok(mut server) = TcpStream::connect(target).await {
+                                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                            }
+                        });
+                        conns.lock().push(conn.abort_handle());
+                    }
+                });
+                tasks.lock().push(accept.abort_handle());
+                Proxy { addr, target, tasks }
+            }
+
+            fn cut(&self) {
+                for task in self.tasks.lock().drain(..) {
+                    task.abort();
+                }
+            }
+
+            async fn restore(self) -> Proxy {
+                Proxy::start(self.addr, self.target).await
+            }
+        }
+
+        fn message_channel() -> (mpsc::UnboundedSender<WsMessage>, mpsc::UnboundedReceiver<WsMessage>) {
+            mpsc::unbounded_channel()
+        }
+
+        #[test]
+        fn test_resume_replays_missed_changes() {
+            let mut host = HostSession::default();
+            let (tx, _rx) = outbox();
+            let first = host.add_peer(tx, Some("guest".to_string()));
+            let ins = |text: &str, len| Operation::from_changes(len, &[TextChange { text: text.to_string(), start: 0, end: 0 }]).unwrap();
+            host.commit(ins("a", 0), Some(first));
+            host.commit(ins("b", 1), None);
+            host.remove_peer(first);
+
+            // The same client comes back having only seen revision 0
+            let (tx, mut rx) = outbox();
+            let second = host.add_peer(tx, Some("guest".to_string()));
+            assert!(host.resume_peer(second, 0));
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Ack { revision: 1 });
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                WsMessage::Change { revision: 2, changes: vec![TextChange { text: "b".to_string(), start: 0, end: 0 }] }
+            );
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Resumed { revision: 2 });
+
+            // Revisions the host never had need a snapshot
+            assert!(!host.resume_peer(second, 3));
+        }
+
+        #[test]
+        fn test_full_outbox_cuts_the_peer_off() {
+            let mut host = HostSession::default();
+            let (tx, mut rx) = Outbox::new(2);
+            host.add_peer(tx.clone(), None);
+            let ins = |len| Operation::from_changes(len, &[TextChange { text: "x".to_string(), start: 0, end: 0 }]).unwrap();
+            host.commit(ins(0), None);
+            host.commit(ins(1), None);
+            assert!(!tx.has_overflowed());
+
+            // The peer is not reading; the host does not wait for it
+            host.commit(ins(2), None);
+            assert!(tx.has_overflowed());
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 1, .. }));
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 2, .. }));
+            assert!(rx.try_recv().is_err());
+        }
+
+        #[test]
+        fn test_nothing_is_queued_after_an_overflow() {
+            let mut host = HostSession::default();
+            let (tx, mut rx) = Outbox::new(2);
+            host.add_peer(tx.clone(), None);
+            let ins = |len| Operation::from_changes(len, &[TextChange { text: "x".to_string(), start: 0, end: 0 }]).unwrap();
+            for len in 0..3 {
+                host.commit(ins(len), None);
+            }
+            assert!(tx.has_overflowed());
+
+            // The writer frees a slot, but revision 3 was lost, so 4 must not follow 2
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 1, .. }));
+            host.commit(ins(3), None);
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 2, .. }));
+            assert!(rx.try_recv().is_err());
+        }
+
+        #[test]
+        fn test_one_snapshot_waits_for_a_peer_sending_rejected_changes() {
+            let mut host = HostSession::default();
+            let (tx, mut rx) = outbox();
+            let peer = host.add_peer(tx.clone(), None);
+            assert_eq!(host.resync(peer, || "text".to_string()), Resync::Sent);
+
+            // Not written yet, so nothing more is queued
+            host.send_to(peer, WsMessage::Ack { revision: 0 });
+            for _ in 1..session::MAX_REJECTIONS {
+                assert_eq!(host.resync(peer, || unreachable!()), Resync::Pending);
+            }
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Snapshot { text: "text".to_string(), revision: 0 });
+            assert_eq!(host.resync(peer, || "again".to_string()), Resync::Sent);
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Ack { revision: 0 });
+
+            // A peer that never reads what it is sent is cut off
+            for _ in 1..session::MAX_REJECTIONS {
+                assert_eq!(host.resync(peer, || unreachable!()), Resync::Pending);
+            }
+            assert_eq!(host.resync(peer, || unreachable!()), Resync::Closed);
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Snapshot { text: "again".to_string(), revision: 0 });
+            assert!(rx.try_recv().is_err());
+        }
+
+        #[test]
+        fn test_resume_too_far_behind_needs_a_snapshot() {
+            let mut host = HostSession::default();
+            let ins = |len| Operation::from_changes(len, &[TextChange { text: "x".to_string(), start: 0, end: 0 }]).unwrap();
+            for len in 0..3 {
+                host.commit(ins(len), None);
+            }
+            let (tx, mut rx) = Outbox::new(3);
+            let peer = host.add_peer(tx.clone(), Some("guest".to_string()));
+            assert!(!host.resume_peer(peer, 0));
+            assert!(rx.try_recv().is_err());
+            assert!(!tx.has_overflowed());
+        }
+
+        #[test]
+        fn test_guest_holds_changes_while_disconnected() {
+            let (tx, mut rx) = message_channel();
+            let mut guest = session::GuestSession::new(4, tx);
+            guest.disconnected();
+            guest.local(Operation::from_changes(0, &[TextChange { text: "x".to_string(), start: 0, end: 0 }]).unwrap());
+            assert!(rx.try_recv().is_err());
+
+            guest.resumed(6);
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                WsMessage::Change { revision: 6, changes: vec![TextChange { text: "x".to_string(), start: 0, end: 0 }] }
+            );
+        }
+
+        #[test]
+        fn test_guest_resends_held_messages_after_resuming() {
+            let (tx, mut rx) = message_channel();
+            let mut guest = session::GuestSession::new(4, tx);
+            guest.local(Operation::from_changes(0, &[TextChange { text: "x".to_string(), start: 0, end: 0 }]).unwrap());
+            let change = rx.try_recv().unwrap();
+
+            // Queued when the connection dropped, never written to it
+            guest.disconnected();
+            guest.hold(change);
+            guest.hold(WsMessage::Chat { author: String::new(), text: "hi".to_string() });
+            guest.hold(WsMessage::Cursor { revision: 4, offset: 1 });
+            guest.hold(WsMessage::Save);
+            assert!(rx.try_recv().is_err());
+
+            guest.resumed(6);
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 6, .. }));
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Chat { author: String::new(), text: "hi".to_string() });
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Save);
+            assert!(rx.try_recv().is_err());
+        }
+
+        #[test]
+        fn test_snapshot_after_reconnecting_warns_about_lost_edits() {
+            let (tx, mut rx) = message_channel();
+            let mut editor = headless_editor(None).expect("Failed to create editor");
+            editor.session = Session::Guest(session::GuestSession::new(0, tx));
+            editor.process_keypress(&Key::Char('x')).unwrap();
+            editor.broadcast_change(&Key::Char('x'), 1).unwrap();
+            assert!(matches!(rx.try_recv().unwrap(), WsMessage::Change { revision: 0, .. }));
+
+            if let Session::Guest(guest) = &mut editor.session {
+                guest.disconnected();
+                guest.hold(WsMessage::Chat { author: String::new(), text: "hi".to_string() });
+            }
+            editor.handle_host_message(WsMessage::Snapshot { text: "fresh".to_string(), revision: 9 }).unwrap();
+            assert_eq!(editor.text(), "fresh");
+            assert!(editor.status_message.contains("edits it had not received were lost"));
+            assert_eq!(rx.try_recv().unwrap(), WsMessage::Chat { author: String::new(), text: "hi".to_string() });
+        }
+
+        #[tokio::test]
+        async fn test_guest_reconnects_and_catches_up() {
+            let (host, addr, server) = host("Hello");
+            let proxy = Proxy::start(([127, 0, 0, 1], 0).into(), addr).await;
+            let guest = join(proxy.addr, "").await;
+
+            proxy.cut();
+            sleep(Duration::from_millis(100)).await;
+            assert!(guest.with(|guest| guest.status_message.clone()).await.starts_with("Disconnected from host"));
+
+            // Both sides keep editing while the network is down
+            host.with(|host| {
+                host.cursor_x = 5;
+                host.process_keypress(&Key::Char('!')).unwrap();
+                host.broadcast_change(&Key::Char('!'), 6).unwrap();
+            })
+            .await;
+            guest
+                .with(|guest| {
+                    guest.process_keypress(&Key::Char('>')).unwrap();
+                    guest.broadcast_change(&Key::Char('>'), 1).unwrap();
+                })
+                .await;
+
+            let proxy = proxy.restore().await;
+            sleep(Duration::from_millis(1000)).await;
+
+            assert_eq!(host.with(|host| host.text()).await, ">Hello!");
+            assert_eq!(guest.with(|guest| guest.text()).await, ">Hello!");
+            assert!(guest.with(|guest| guest.status_message.clone()).await.starts_with("Reconnected"));
+            proxy.cut();
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_silent_peer_outlives_the_timeout_while_others_type() {
+            use text_editor::protocol::{HEARTBEAT_INTERVAL, PEER_TIMEOUT};
+
+            let (host, addr, server) = host("");
+            let mut silent = connect(addr, "silent", None).await;
+            // It sends nothing, but answers pings while reading
+            let reader = tokio::spawn(async move { while next_message(&mut silent).await.is_some() {} });
+
+            let typing = tokio::time::Instant::now();
+            while typing.elapsed() < PEER_TIMEOUT + HEARTBEAT_INTERVAL + Duration::from_secs(2) {
+                host.with(|host| {
+                    host.process_keypress(&Key::Char('x')).unwrap();
+                    let start = host.cursor_offset();
+                    host.broadcast_change(&Key::Char('x'), start).unwrap();
+                })
+                .await;
+                sleep(Duration::from_millis(500)).await;
+            }
+
+            assert!(!reader.is_finished());
+            let peers = host.with(|host| match &host.session {
+                Session::Host(host) => host.peer_count(),
+                Session::Guest(_) => 0,
+            });
+            assert_eq!(peers.await, 1);
+            server.abort();
+        }
+    }
+
+    mod oplog_tests {
+        use super::*;
+        use clap::Parser;
+        use cli::{Cli, Command};
+        use oplog::OpRecord;
+
+        #[test]
+        fn test_replay_any_revision() {
+            let records = vec![
+                OpRecord::new(3, None, vec![change("Hello", 0, 0)]),
+                OpRecord::new(4, Some("a".to_string()), vec![change(" world", 5, 5)]),
+                OpRecord::new(5, Some("b".to_string()), vec![change("J", 0, 1), change("", 6, 11)]),
+            ];
+            assert_eq!(oplog::replay(&records, Some(3)).unwrap(), "Hello");
+            assert_eq!(oplog::replay(&records, Some(4)).unwrap(), "Hello world");
+            assert_eq!(oplog::replay(&records, None).unwrap(), "Jello ");
+            assert!(matches!(oplog::replay(&records, Some(2)), Err(OtError::UnknownRevision(2))));
+            assert!(matches!(oplog::replay(&records, Some(6)), Err(OtError::UnknownRevision(6))));
+        }
+
+        #[tokio::test]
+        async fn test_host_writes_op_log() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("ops.jsonl");
+            let mut editor = headless_editor(None).unwrap();
+            editor.set_text("abc");
+            editor.start_op_log(&path).unwrap();
+
+            let (tx, _rx) = outbox();
+            let peer = editor.add_peer(tx, ConnectParams { client: Some("guest".to_string()), ..ConnectParams::default() }).unwrap();
+            editor.apply_remote_change(peer, 0, &[change("X", 1, 2)]).unwrap();
+            editor.cursor_x = 3;
+            editor.process_keypress(&Key::Char('!')).unwrap();
+            editor.broadcast_change(&Key::Char('!'), 4).unwrap();
+
+            let records = oplog::load(&path).unwrap();
+            let authors: Vec<_> = records.iter().map(|r| (r.revision, r.author.clone())).collect();
+            assert_eq!(authors, vec![(0, None), (1, Some("guest".to_string())), (2, None)]);
+            assert_eq!(oplog::replay(&records, Some(0)).unwrap(), "abc");
+            assert_eq!(oplog::replay(&records, Some(1)).unwrap(), "aXc");
+            assert_eq!(oplog::replay(&records, None).unwrap(), editor.text());
+        }
+
+        #[tokio::test]
+        async fn test_restarted_host_adds_a_session_to_the_log() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("ops.jsonl");
+            for (text, c) in [("abc", '1'), ("abc1", '2')] {
+                let mut editor = headless_editor(None).unwrap();
+                editor.set_text(text);
+                editor.start_op_log(&path).unwrap();
+                editor.cursor_x = editor.content[0].len();
+                editor.process_keypress(&Key::Char(c)).unwrap();
+                let start = editor.cursor_offset();
+                editor.broadcast_change(&Key::Char(c), start).unwrap();
+            }
+
+            let records = oplog::load(&path).unwrap();
+            let sessions = oplog::sessions(&records);
+            assert_eq!(sessions.len(), 2);
+            assert_eq!(oplog::replay(sessions[0], Some(0)).unwrap(), "abc");
+            assert_eq!(oplog::replay(sessions[0], None).unwrap(), "abc1");
+            assert_eq!(oplog::replay(sessions[1], Some(0)).unwrap(), "abc1");
+            assert_eq!(oplog::replay(sessions[1], None).unwrap(), "abc12");
+
+            // Logs from before sessions were marked are one session
+            let old = create_test_file("{\"revision\":0,\"author\":null,\"timestamp\":0,\"changes\":[{\"text\":\"x\",\"start\":0,\"end\":0}]}\n");
+            let records = oplog::load(old.path()).unwrap();
+            assert_eq!(oplog::sessions(&records).len(), 1);
+            assert_eq!(oplog::replay(&records, None).unwrap(), "x");
+        }
+
+        #[test]
+        fn test_load_reports_bad_lines() {
+            let file = create_test_file("{\"revision\":0,\"author\":null,\"timestamp\":0,\"changes\":[]}\nnot json\n");
+            let err = oplog::load(file.path()).unwrap_err();
+            assert!(err.to_string().contains("line 2"));
+        }
+
+        #[test]
+        fn test_replay_subcommand() {
+            let cli = Cli::try_parse_from(["text_editor", "replay", "ops.jsonl", "--revision", "7"]).expect("Failed to parse");
+            let Some(Command::Replay(args)) = cli.command else {
+                panic!("expected the replay subcommand");
+            };
+            assert_eq!(args.log, std::path::PathBuf::from("ops.jsonl"));
+            assert_eq!(args.revision, Some(7));
+            assert_eq!(args.session, None);
+            assert!(!args.history);
+
+            let cli = Cli::try_parse_from(["text_editor", "replay", "ops.jsonl", "--session", "2", "--history"]).unwrap();
+            let Some(Command::Replay(args)) = cli.command else {
+                panic!("expected the replay subcommand");
+            };
+            assert_eq!(args.session, Some(2));
+
+            assert!(Cli::try_parse_from(["text_editor", "--join", "ws://x/ws", "--op-log", "ops.jsonl"]).is_err());
+        }
+    }
+
+    mod file_tests {
+        use super::*;
+        use file::{Encoding, FileFormat, LineEnding};
+
+        #[test]
+        fn test_decode_encode_round_trip() {
+            for raw in ["", "\n", "a", "a\n", "a\n\n", "a\r\nb\r\n", "a\r\nb", "a\r\nb\n", "a\rb\n", "\r\n\r\n"] {
+                let (lines, format) = file::decode(raw);
+                assert_eq!(file::encode(&lines, format), raw, "round trip of {:?}", raw);
+            }
+        }
+
+        #[test]
+        fn test_decode_detects_format() {
+            let (lines, format) = file::decode("one\r\ntwo\r\n");
+            assert_eq!(lines, vec!["one", "two"]);
+            assert_eq!(format, FileFormat { encoding: Encoding::Utf8, line_ending: LineEnding::CrLf, final_newline: true });
+
+            // Mixed endings keep the stray carriage returns in the text
+            let (lines, format) = file::decode("one\r\ntwo\n");
+            assert_eq!(lines, vec!["one\r", "two"]);
+            assert_eq!(format, FileFormat { encoding: Encoding::Utf8, line_ending: LineEnding::Lf, final_newline: true });
+        }
+
+        #[test]
+        fn test_save_preserves_line_endings() {
+            let file = create_test_file("Hello\r\nWorld\r\n");
+            let filename = file.path().to_string_lossy().to_string();
+            let mut editor = headless_editor(Some(filename.clone())).unwrap();
+            assert_eq!(editor.content, vec!["Hello", "World"]);
+
+            editor.content.push("Again".to_string());
+            editor.save_file().unwrap();
+            assert_eq!(fs::read_to_string(&filename).unwrap(), "Hello\r\nWorld\r\nAgain\r\n");
+        }
+
+        #[test]
+        fn test_save_keeps_backup_and_no_temp_files() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("notes.txt");
+            fs::write(&path, "old\n").unwrap();
+
+            file::save_atomic(&path, b"new\n", true).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+            assert_eq!(fs::read_to_string(file::backup_path(&path)).unwrap(), "old\n");
+
+            let names: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+            assert_eq!(names.len(), 2, "unexpected files: {:?}", names);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_save_preserves_mode_and_symlinks() {
+            use std::os::unix::fs::{symlink, PermissionsExt};
+
+            let dir = tempfile::tempdir().unwrap();
+            let target = dir.path().join("script.sh");
+            fs::write(&target, "echo hi\n").unwrap();
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o750)).unwrap();
+            let link = dir.path().join("link.sh");
+            symlink(&target, &link).unwrap();
+
+            file::save_atomic(&link, b"echo bye\n", false).unwrap();
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read_to_string(&target).unwrap(), "echo bye\n");
+            assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o750);
+        }
+
+        #[test]
+        fn test_detect_encodings() {
+            let cases: [(&[u8], Encoding); 5] = [
+                (b"caf\xC3\xA9", Encoding::Utf8),
+                (b"\xEF\xBB\xBFcaf\xC3\xA9", Encoding::Utf8Bom),
+                (b"\xFF\xFEc\0a\0f\0\xE9\0", Encoding::Utf16Le),
+                (b"\xFE\xFF\0c\0a\0f\0\xE9", Encoding::Utf16Be),
+                (b"caf\xE9", Encoding::Latin1),
+            ];
+            for (bytes, encoding) in cases {
+                let (text, detected) = file::from_bytes(bytes).unwrap();
+                assert_eq!((text.as_str(), detected), ("café", encoding));
+                assert_eq!(file::to_bytes(&text, encoding).unwrap(), bytes);
+            }
+            assert!(file::to_bytes("€", Encoding::Latin1).is_err());
+        }
+
+        #[test]
+        fn test_editor_keeps_encoding_on_save() {
+            let file = NamedTempFile::new().unwrap();
+            fs::write(file.path(), b"\xFF\xFEa\0\r\0\n\0").unwrap();
+            let filename = file.path().to_string_lossy().to_string();
+
+            let mut editor = headless_editor(Some(filename.clone())).unwrap();
+            assert_eq!(editor.content, vec!["a"]);
+            assert_eq!(editor.format, FileFormat { encoding: Encoding::Utf16Le, line_ending: LineEnding::CrLf, final_newline: true });
+            editor.content[0].push('b');
+            editor.save_file().unwrap();
+            assert_eq!(fs::read(&filename).unwrap(), b"\xFF\xFEa\0b\0\r\0\n\0");
+        }
+
+        #[test]
+        fn test_unreadable_file_is_an_error() {
+            let file = NamedTempFile::new().unwrap();
+            fs::write(file.path(), b"\xFF\xFE\0\xD8").unwrap();
+            let err = headless_editor(Some(file.path().to_string_lossy().to_string())).err().expect("Expected an error");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            let dir = tempfile::tempdir().unwrap();
+            assert!(headless_editor(Some(dir.path().to_string_lossy().to_string())).is_err());
+
+            // A file that does not exist yet is simply a new document
+            let missing = dir.path().join("new.txt").to_string_lossy().to_string();
+            assert_eq!(headless_editor(Some(missing)).unwrap().content, vec![""]);
+        }
+
+        #[test]
+        fn test_save_to_missing_directory_fails_cleanly() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("missing").join("notes.txt");
+            assert!(file::save_atomic(&path, b"text", false).is_err());
+        }
+    }
+
+    mod prompt_tests {
+        use super::*;
+        use prompt::{complete_path, Prompt, PromptEvent, PromptKind};
+
+        #[test]
+        fn test_prompt_editing_and_history() {
+            let history = vec!["first".to_string(), "second".to_string()];
+            let mut prompt = Prompt::new(PromptKind::Search, "");
+            for key in [Key::Char('a'), Key::Char('c'), Key::Left, Key::Char('b'), Key::End, Key::Char('d')] {
+                prompt.handle_key(&key, &history);
+            }
+            assert_eq!(prompt.render(), "Search: abcd");
+            assert_eq!(prompt.cursor_col(), "Search: abcd".len());
+
+            prompt.handle_key(&Key::Up, &history);
+            prompt.handle_key(&Key::Up, &history);
+            assert_eq!(prompt.render(), "Search: first");
+            prompt.handle_key(&Key::Down, &history);
+            prompt.handle_key(&Key::Down, &history);
+            assert_eq!(prompt.render(), "Search: abcd");
+
+            prompt.handle_key(&Key::Backspace, &history);
+            assert!(matches!(prompt.handle_key(&Key::Enter, &history), PromptEvent::Submit(input) if input == "abc"));
+            assert!(matches!(prompt.handle_key(&Key::Escape, &history), PromptEvent::Cancel));
+        }
+
+        #[test]
+        fn test_prompt_is_clipped_to_the_terminal_width() {
+            let mut prompt = Prompt::new(PromptKind::Search, "abcdef");
+            assert_eq!(prompt.render_within(40), ("Search: abcdef".to_string(), 14));
+            // The cursor at the end stays in view
+            assert_eq!(prompt.render_within(10), ("h: abcdef".to_string(), 9));
+            prompt.handle_key(&Key::Home, &[]);
+            assert_eq!(prompt.render_within(10), ("Search: ab".to_string(), 8));
+            // A question keeps its start
+            let prompt = Prompt::new(PromptKind::Search, "");
+            assert_eq!(prompt.render_within(5), ("Searc".to_string(), 4));
+        }
+
+        #[test]
+        fn test_path_completion() {
+            let dir = tempfile::tempdir().unwrap();
+            fs::write(dir.path().join("notes.txt"), "").unwrap();
+            fs::write(dir.path().join("novel.md"), "").unwrap();
+            fs::write(dir.path().join(".hidden"), "").unwrap();
+            fs::create_dir(dir.path().join("src")).unwrap();
+            let base = format!("{}/", dir.path().display());
+
+            assert_eq!(complete_path(&format!("{}s", base)), vec![format!("{}src/", base)]);
+            assert_eq!(complete_path(&format!("{}.", base)), vec![format!("{}.hidden", base)]);
+            assert_eq!(complete_path(&base).len(), 3);
+
+            let mut prompt = Prompt::new(PromptKind::Open, &format!("{}no", base));
+            prompt.handle_key(&Key::Ctrl(b'i'), &[]);
+            assert_eq!(prompt.render(), format!("Open file: {}no  [notes.txt novel.md]", base));
+            prompt.handle_key(&Key::Char('t'), &[]);
+            prompt.handle_key(&Key::Ctrl(b'i'), &[]);
+            assert_eq!(prompt.render(), format!("Open file: {}notes.txt", base));
+        }
+
+        #[test]
+        fn test_save_as_unnamed_buffer() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("new.txt").to_string_lossy().to_string();
+            let mut editor = headless_editor(None).unwrap();
+            type_str(&mut editor, "hi");
+
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            assert!(editor.prompt.is_some());
+            answer(&mut editor, &path);
+
+            assert!(editor.prompt.is_none());
+            assert_eq!(editor.filename.as_deref(), Some(path.as_str()));
+            assert!(!editor.modified);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "hi");
+        }
+
+        #[test]
+        fn test_goto_line_and_search() {
+            let mut editor = headless_editor(None).unwrap();
+            editor.set_text("one\ntwo\nthree\ntwo again");
+
+            editor.process_keypress(&Key::Ctrl(b'g')).unwrap();
+            answer(&mut editor, "3");
+            assert_eq!((editor.cursor_y, editor.cursor_x), (2, 0));
+
+            editor.process_keypress(&Key::Ctrl(b'f')).unwrap();
+            answer(&mut editor, "two");
+            assert_eq!((editor.cursor_y, editor.cursor_x), (3, 0));
+
+            // Ctrl+N finds the next match, wrapping around
+            editor.process_keypress(&Key::Ctrl(b'n')).unwrap();
+            assert_eq!((editor.cursor_y, editor.cursor_x), (1, 0));
+            assert!(editor.status_message.starts_with("Search wrapped"));
+
+            editor.process_keypress(&Key::Ctrl(b'f')).unwrap();
+            answer(&mut editor, "missing");
+            assert_eq!(editor.status_message, "Not found: missing");
+        }
+
+        #[test]
+        fn test_open_file_replaces_shared_document() {
+            let file = create_test_file("opened\r\n");
+            let mut editor = headless_editor(None).unwrap();
+            editor.set_text("old");
+            let (tx, mut rx) = outbox();
+            editor.add_peer(tx, ConnectParams::default()).unwrap();
+            while rx.try_recv().is_ok() {}
+
+            editor.process_keypress(&Key::Ctrl(b'o')).unwrap();
+            answer(&mut editor, &file.path().to_string_lossy());
+
+            assert_eq!(editor.content, vec!["opened"]);
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                WsMessage::Change { revision: 1, changes: vec![TextChange { text: "opened".to_string(), start: 0, end: 3 }] }
+            );
+        }
+    }
+
+    mod external_change_tests {
+        use super::*;
+
+        #[test]
+        fn test_unchanged_file_is_left_alone() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "same\n");
+            assert!(!editor.check_disk());
+            fs::write(&path, "same\n").unwrap();
+            assert!(!editor.check_disk());
+            assert!(editor.prompt.is_none());
+        }
+
+        #[test]
+        fn test_reload_sends_only_the_difference() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "one\ntwo\n");
+            let (tx, mut rx) = outbox();
+            editor.add_peer(tx, ConnectParams::default()).unwrap();
+            while rx.try_recv().is_ok() {}
+
+            fs::write(&path, "one\n2\n").unwrap();
+            assert!(editor.check_disk());
+            assert!(editor.prompt.is_some());
+            answer(&mut editor, "r");
+
+            assert_eq!(editor.content, vec!["one", "2"]);
+            assert!(!editor.modified);
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                WsMessage::Change { revision: 1, changes: vec![TextChange { text: "2".to_string(), start: 4, end: 7 }] }
+            );
+        }
+
+        #[test]
+        fn test_remote_save_of_a_file_changed_on_disk_is_answered() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "one\n");
+            let (tx, mut rx) = outbox();
+            let peer = editor.add_peer(tx, ConnectParams::default()).unwrap();
+            while rx.try_recv().is_ok() {}
+
+            fs::write(&path, "two\n").unwrap();
+            editor.remote_save(peer);
+            assert!(editor.prompt.is_some());
+            let reply = std::iter::from_fn(|| rx.try_recv().ok()).find(|m| matches!(m, WsMessage::SaveResult { .. }));
+            let Some(WsMessage::SaveResult { error: Some(error), .. }) = reply else {
+                panic!("Expected a failed save result, got {:?}", reply);
+            };
+            assert!(error.contains("changed on disk"), "{}", error);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        }
+
+        #[test]
+        fn test_merge_with_and_without_conflicts() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "a\nb\nc\nd\ne\n");
+            editor.set_text("A\nb\nc\nd\ne");
+            fs::write(&path, "a\nb\nc\nd\nE\n").unwrap();
+            assert!(editor.check_disk());
+            answer(&mut editor, "m");
+            assert_eq!(editor.content, vec!["A", "b", "c", "d", "E"]);
+            assert!(editor.modified);
+
+            editor.set_text("A\nb\nmine\nd\nE");
+            fs::write(&path, "a\nb\ntheirs\nd\nE\n").unwrap();
+            assert!(editor.check_disk());
+            answer(&mut editor, "m");
+            assert_eq!(editor.text(), "A\nb\n<<<<<<< ours\nmine\n=======\ntheirs\n>>>>>>> theirs\nd\nE");
+            assert!(editor.status_message.contains("conflicts"));
+        }
+
+        #[test]
+        fn test_save_asks_before_overwriting_and_keep() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "mine\n");
+            editor.content[0] = "edited".to_string();
+            editor.modified = true;
+            fs::write(&path, "changed elsewhere\n").unwrap();
+
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            assert!(editor.prompt.is_some());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "changed elsewhere\n");
+
+            answer(&mut editor, "k");
+            assert!(!editor.check_disk());
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "edited\n");
+        }
+    }
+
+    mod swap_tests {
+        use super::*;
+        use std::time::Duration;
+
+        fn edit(editor: &mut Editor, c: char) {
+            editor.process_keypress(&Key::Char(c)).unwrap();
+            let start = editor.cursor_offset();
+            editor.broadcast_change(&Key::Char(c), start).unwrap();
+        }
+
+        #[test]
+        fn test_swap_written_after_edits_and_removed_on_save() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "text\n");
+            let swap = file::swap_path(&path);
+
+            editor.autosave_tick();
+            assert!(!swap.exists());
+
+            edit(&mut editor, '>');
+            editor.autosave_tick();
+            assert_eq!(fs::read_to_string(&swap).unwrap(), ">text\n");
+
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            assert!(!swap.exists());
+            assert_eq!(fs::read_to_string(&path).unwrap(), ">text\n");
+        }
+
+        #[test]
+        fn test_recover_from_swap() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "saved\n");
+            fs::write(file::swap_path(&path), "saved\nand lost\n").unwrap();
+
+            assert!(editor.offer_recovery());
+            editor.process_keypress(&Key::Char('r')).unwrap();
+            editor.process_keypress(&Key::Enter).unwrap();
+            assert_eq!(editor.content, vec!["saved", "and lost"]);
+            assert!(editor.modified);
+
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            assert!(!file::swap_path(&path).exists());
+        }
+
+        #[test]
+        fn test_swap_leaves_other_editors_files_alone() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "text\n");
+            // What vim keeps for the same file
+            let vim = dir.path().join(".notes.txt.swp");
+            fs::write(&vim, "vim's own").unwrap();
+
+            assert!(!editor.offer_recovery());
+            edit(&mut editor, '>');
+            editor.autosave_tick();
+            editor.process_keypress(&Key::Ctrl(b's')).unwrap();
+            editor.remove_swap();
+            assert_eq!(fs::read_to_string(&vim).unwrap(), "vim's own");
+            assert!(!file::swap_path(&path).exists());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_swap_is_no_more_readable_than_the_file() {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "secret\n");
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            edit(&mut editor, '>');
+            editor.autosave_tick();
+            assert_eq!(mode(&file::swap_path(&path)), 0o600);
+
+            // A file not saved yet has nothing to copy, its swap is private
+            let new = dir.path().join("new.txt");
+            let mut editor = headless_editor(Some(new.to_string_lossy().to_string())).unwrap();
+            edit(&mut editor, 'x');
+            editor.autosave_tick();
+            assert_eq!(mode(&file::swap_path(&new)), 0o600);
+        }
+
+        #[test]
+        fn test_discard_or_ignore_stale_swap() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "saved\n");
+            let swap = file::swap_path(&path);
+
+            // A swap file matching the file has nothing worth recovering
+            fs::write(&swap, "saved\n").unwrap();
+            assert!(!editor.offer_recovery());
+            assert!(!swap.exists());
+
+            fs::write(&swap, "other\n").unwrap();
+            assert!(editor.offer_recovery());
+            editor.process_keypress(&Key::Char('d')).unwrap();
+            editor.process_keypress(&Key::Enter).unwrap();
+            assert_eq!(editor.content, vec!["saved"]);
+            assert!(!swap.exists());
+        }
+
+        #[test]
+        fn test_autosave_after_idle() {
+            let dir = tempfile::tempdir().unwrap();
+            let (path, mut editor) = open(&dir, "a\n");
+            editor.autosave = Some(Duration::from_millis(50));
+
+            edit(&mut editor, 'b');
+            editor.autosave_tick();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "a\n");
+
+            std::thread::sleep(Duration::from_millis(60));
+            assert!(editor.autosave_tick());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "ba\n");
+            assert!(!editor.modified);
+            assert!(editor.status_message.starts_with("Autosaved"));
+        }
+    }
+
+    mod quit_tests {
+        use super::*;
+        use tokio::time::{sleep, Duration};
+
+        fn edited(content: &str) -> (NamedTempFile, Editor) {
+            let file = create_test_file(content);
+            let mut editor = headless_editor(Some(file.path().to_string_lossy().to_string())).unwrap();
+            editor.process_keypress(&Key::Char('x')).unwrap();
+            (file, editor)
+        }
+
+        #[test]
+        fn test_quit_without_changes() {
+            let mut editor = headless_editor(None).unwrap();
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            assert!(editor.prompt.is_none());
+            assert!(editor.shutdown.token.is_cancelled());
+        }
+
+        #[test]
+        fn test_cancel_keeps_changes() {
+            let (_file, mut editor) = edited("abc\n");
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            assert!(editor.prompt.as_ref().unwrap().render().starts_with("Unsaved changes"));
+
+            answer(&mut editor, "c");
+            assert!(editor.modified);
+            assert!(!editor.shutdown.token.is_cancelled());
+
+            // Pressing Ctrl+Q again asks again rather than quitting
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            editor.process_keypress(&Key::Escape).unwrap();
+            assert!(editor.modified);
+            assert!(!editor.shutdown.token.is_cancelled());
+        }
+
+        #[test]
+        fn test_save_or_discard_and_quit() {
+            let (file, mut editor) = edited("abc\n");
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            answer(&mut editor, "d");
+            assert!(editor.shutdown.token.is_cancelled());
+            assert_eq!(fs::read_to_string(file.path()).unwrap(), "abc\n");
+
+            let (file, mut editor) = edited("abc\n");
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            answer(&mut editor, "s");
+            assert!(editor.shutdown.token.is_cancelled());
+            assert_eq!(fs::read_to_string(file.path()).unwrap(), "xabc\n");
+        }
+
+        #[test]
+        fn test_save_unnamed_buffer_before_quitting() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("new.txt");
+            let mut editor = headless_editor(None).unwrap();
+            editor.process_keypress(&Key::Char('x')).unwrap();
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            answer(&mut editor, "s");
+            assert!(!editor.shutdown.token.is_cancelled());
+
+            // Not quitting when the name prompt is cancelled
+            editor.process_keypress(&Key::Escape).unwrap();
+            assert!(!editor.shutdown.token.is_cancelled());
+
+            editor.process_keypress(&Key::Ctrl(b'q')).unwrap();
+            answer(&mut editor, "s");
+            answer(&mut editor, &path.to_string_lossy());
+            assert!(editor.shutdown.token.is_cancelled());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "x");
+        }
+
+        #[tokio::test]
+        async fn test_host_quitting_ends_the_session() {
+            let (host, addr, server) = host("Hello");
+            let guest = join(addr, "").await;
+
+            host.with(|host| host.process_keypress(&Key::Ctrl(b'q')).unwrap()).await;
+            assert!(host.shutdown().token.is_cancelled());
+            sleep(Duration::from_millis(500)).await;
+
+            // The guest keeps its copy and stops trying to reconnect
+            let (text, status, hosting) = guest
+                .with(|guest| (guest.text(), guest.status_message.clone(), matches!(guest.session, Session::Host(_))))
+                .await;
+            assert_eq!(text, "Hello");
+            assert!(status.starts_with("Session ended"), "{}", status);
+            assert!(hosting);
+            server.abort();
+        }
+    }
+
+    mod serve_tests {
+        use super::*;
+        use futures_util::SinkExt;
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+        use tokio_tungstenite::connect_async;
+        use tungstenite::Message;
+
+        #[tokio::test]
+        async fn test_serve_saves_and_exits_on_sigterm() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shared.txt");
+            fs::write(&path, "Hello").unwrap();
+
+            let mut child = Command::new(env!("CARGO_BIN_EXE_text_editor"))
+                .args(["serve", path.to_str().unwrap(), "--port", "0"])
+                .env("XDG_STATE_HOME", dir.path().join("state"))
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("Failed to start the server");
+            let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+            let url = lines
+                .by_ref()
+                .find_map(|line| line.ok()?.split("listening on ").nth(1).map(str::to_string))
+                .expect("Server did not report its address");
+
+            let (mut ws, _) = connect_async(url.as_str()).await.expect("Failed to connect");
+            let Some(WsMessage::Snapshot { text, revision }) = next_message(&mut ws).await else {
+                panic!("Expected a snapshot");
+            };
+            assert_eq!(text, "Hello");
+            // Nobody sits at the server, so there is no cursor of its own
+            assert!(matches!(next_message(&mut ws).await, Some(WsMessage::Joined { role: Role::Editor, .. })));
+
+            let change = WsMessage::Change { revision, changes: vec![TextChange { text: "!".to_string(), start: 5, end: 5 }] };
+            ws.send(Message::Text(serde_json::to_string(&change).unwrap())).await.unwrap();
+            assert!(matches!(next_message(&mut ws).await, Some(WsMessage::Ack { .. })));
+
+            let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+            assert!(status.success());
+            loop {
+                match next_message(&mut ws).await {
+                    Some(WsMessage::SessionEnd { .. }) => break,
+                    Some(WsMessage::SaveResult { error, .. }) => assert_eq!(error, None),
+                    other => panic!("Unexpected message {:?}", other),
+                }
+            }
+
+            assert!(child.wait().unwrap().success());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "Hello!");
+            assert!(!file::swap_path(&path).exists());
+
+            // Logged to a daily file in the state directory
+            let logs: Vec<_> = fs::read_dir(dir.path().join("state/text_editor")).unwrap().map(|e| e.unwrap().path()).collect();
+            assert_eq!(logs.len(), 1, "{:?}", logs);
+            assert!(logs[0].file_name().unwrap().to_string_lossy().starts_with("text_editor.log.20"));
+            assert!(fs::read_to_string(&logs[0]).unwrap().contains("Shutting down"));
+        }
+
+        #[test]
+        fn test_unattended_editor_reloads_external_changes() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shared.txt");
+            fs::write(&path, "one").unwrap();
+            let mut editor = headless_editor(Some(path.to_string_lossy().to_string())).unwrap();
+            editor.unattended = true;
+
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            fs::write(&path, "two\nlines").unwrap();
+            assert!(editor.check_disk());
+            assert!(editor.prompt.is_none());
+            assert_eq!(editor.content, vec!["two", "lines"]);
+            assert!(!editor.modified);
+        }
+
+        #[test]
+        fn test_merge_on_the_way_out_is_kept_in_the_swap() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shared.txt");
+            fs::write(&path, "one\na\nb\nc\ntwo\n").unwrap();
+            let mut editor = headless_editor(Some(path.to_string_lossy().to_string())).unwrap();
+            editor.unattended = true;
+            editor.content[0] = "ONE".to_string();
+            editor.publish(&[]);
+            editor.modified = true;
+            editor.autosave_tick();
+
+            // What serve does on SIGTERM, with a change made on disk since
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            fs::write(&path, "one\na\nb\nc\nTWO\n").unwrap();
+            editor.save_file().unwrap();
+            assert!(editor.modified);
+            editor.write_swap();
+            assert_eq!(fs::read_to_string(file::swap_path(&path)).unwrap(), "ONE\na\nb\nc\nTWO\n");
+            assert_eq!(fs::read_to_string(&path).unwrap(), "one\na\nb\nc\nTWO\n");
+        }
+    }
+
+    mod screen_tests {
+        use super::*;
+        use std::path::Path;
+        use text_editor::terminal::Screen;
+
+        const ROWS: usize = 10;
+        const COLS: usize = 50;
+
+        fn editor_with_text(text: &str) -> (Editor, Headless) {
+            let terminal = Headless::new(ROWS, COLS);
+            let mut editor = Editor::with_terminal(None, Arc::new(terminal.clone())).unwrap();
+            editor.name = "alice".to_string();
+            editor.set_text(text);
+            (editor, terminal)
+        }
+
+        fn numbered_lines(count: usize) -> String {
+            (1..=count).map(|n| format!("line {}", n)).collect::<Vec<_>>().join("\n")
+        }
+
+        fn press(editor: &mut Editor, keys: &[Key]) {
+            for key in keys {
+                editor.process_keypress(key).unwrap();
+            }
+        }
+
+        fn type_text(editor: &mut Editor, text: &str) {
+            for c in text.chars() {
+                editor.process_keypress(&Key::Char(c)).unwrap();
+            }
+        }
+
+        // Compare what the screen shows with tests/golden/<name>.txt. Run with
+        // UPDATE_GOLDEN=1 to write the files instead.
+        fn assert_screen(editor: &Editor, terminal: &Headless, name: &str) {
+            editor.refresh_screen().unwrap();
+            let actual = format!("{}\n", terminal.screen().expect("Nothing was drawn"));
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.txt", name));
+            if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, &actual).unwrap();
+                return;
+            }
+            let expected = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Cannot read {}: {} (UPDATE_GOLDEN=1 writes it)", path.display(), e));
+            assert_eq!(actual, expected, "Screen differs from {}", path.display());
+        }
+
+        #[test]
+        fn test_screen_render() {
+            let screen = Screen::render("\x1b[2J\x1b[Habc\r\n\x1b[7mde\x1b[m\r\nlonger than four\x1b[2;2H", 3, 4);
+            assert_eq!(screen.rows, vec!["abc ", "de  ", "long"]);
+            assert_eq!(screen.cursor, (1, 1));
+
+            // Writing past the last row scrolls
+            let screen = Screen::render("1\r\n2\r\n3", 2, 2);
+            assert_eq!(screen.rows, vec!["2 ", "3 "]);
+            assert_eq!(screen.cursor, (1, 1));
+        }
+
+        #[test]
+        fn test_screen_empty_document() {
+            let (editor, terminal) = editor_with_text("");
+            assert_screen(&editor, &terminal, "empty_document");
+        }
+
+        #[test]
+        fn test_screen_typing() {
+            let (mut editor, terminal) = editor_with_text("");
+            type_text(&mut editor, "Hello");
+            press(&mut editor, &[Key::Enter]);
+            type_text(&mut editor, "World");
+            assert_screen(&editor, &terminal, "typing");
+        }
+
+        #[test]
+        fn test_screen_scrolls_down() {
+            let (mut editor, terminal) = editor_with_text(&numbered_lines(30));
+            for _ in 0..12 {
+                press(&mut editor, &[Key::Down]);
+            }
+            assert_screen(&editor, &terminal, "scrolled_down");
+
+            for _ in 0..9 {
+                press(&mut editor, &[Key::Up]);
+            }
+            assert_screen(&editor, &terminal, "scrolled_back_up");
+        }
+
+        #[test]
+        fn test_screen_scrolls_right() {
+            let long = (0..8).map(|n| format!("{}123456789", n)).collect::<String>();
+            let (mut editor, terminal) = editor_with_text(&format!("{}\nshort", long));
+            press(&mut editor, &[Key::End]);
+            assert_screen(&editor, &terminal, "scrolled_right");
+
+            press(&mut editor, &[Key::Home]);
+            assert_screen(&editor, &terminal, "scrolled_home");
+        }
+
+        #[test]
+        fn test_screen_goto_line_prompt() {
+            let (mut editor, terminal) = editor_with_text(&numbered_lines(30));
+            press(&mut editor, &[Key::Ctrl(b'g'), Key::Char('2'), Key::Char('0')]);
+            assert_screen(&editor, &terminal, "goto_line_prompt");
+
+            press(&mut editor, &[Key::Enter]);
+            assert_screen(&editor, &terminal, "goto_line_done");
+        }
+
+        #[test]
+        fn test_screen_search_not_found() {
+            let (mut editor, terminal) = editor_with_text("alpha\nbeta");
+            press(&mut editor, &[Key::Ctrl(b'f')]);
+            type_text(&mut editor, "gamma");
+            press(&mut editor, &[Key::Enter]);
+            assert_screen(&editor, &terminal, "search_not_found");
+        }
+
+        #[test]
+        fn test_screen_quit_prompt() {
+            let (mut editor, terminal) = editor_with_text("draft");
+            press(&mut editor, &[Key::End, Key::Char('!'), Key::Ctrl(b'q')]);
+            assert_screen(&editor, &terminal, "quit_prompt");
+        }
+
+        #[test]
+        fn test_screen_comments_and_collaborators() {
+            let (mut editor, terminal) = editor_with_text("fn main() {\n    todo!()\n}");
+            press(&mut editor, &[Key::Down, Key::Ctrl(b'r')]);
+            type_text(&mut editor, "implement");
+            press(&mut editor, &[Key::Enter]);
+            editor.presence.update("1", "bob", "", 3);
+            assert_screen(&editor, &terminal, "comments_and_collaborators");
+        }
+    }
+
+    mod terminal_tests {
+        use super::*;
+        
+        // Note: Terminal tests are limited because they require actual terminal interaction
+        // In a production environment, you might use a mock terminal or test framework
+        
+        #[test]
+        fn test_terminal_size_fallback() {
+            // Test that we handle terminal size detection gracefully
+            // This should not panic even if terminal commands fail
+            let result = Terminal::get_terminal_size();
+            
+            // Should either succeed or use fallback values
+            match result {
+                Ok((rows, cols)) => {
+                    assert!(rows > 0);
+                    assert!(cols > 0);
+                }
+                Err(_) => {
+                    // Terminal commands may fail in test environment
+                    // This is acceptable behavior
+                }
+            }
+        }
+
+        #[test]
+        fn test_headless_editor_draws_to_memory() {
+            let terminal = Headless::new(10, 40);
+            let editor = Editor::with_terminal(None, std::sync::Arc::new(terminal.clone())).unwrap();
+            assert_eq!(editor.terminal_rows, 8);
+            assert_eq!(editor.terminal_cols, 40);
+
+            editor.refresh_screen().unwrap();
+            let frame = terminal.last_frame().expect("Expected a frame");
+            assert!(frame.starts_with(text_editor::terminal::CLEAR_SCREEN));
+            assert!(frame.contains("[No Name] - 1 lines"));
+            assert_eq!(frame.matches('~').count(), 7);
+        }
+
+        #[tokio::test]
+        async fn test_run_headless_with_queued_keys() {
+            let terminal = Headless::new(24, 80);
+            let buffer = Hub::start(Editor::with_terminal(None, Arc::new(terminal.clone())).unwrap());
+            terminal.push_keys([Key::Char('H'), Key::Char('i'), Key::Ctrl(b'q'), Key::Char('d'), Key::Enter]);
+
+            editor::run(buffer.clone()).await.expect("Editor should quit");
+            assert_eq!(buffer.with(|buffer| buffer.content.clone()).await, vec!["Hi"]);
+            assert!(buffer.shutdown().token.is_cancelled());
+            assert!(!terminal.is_raw());
+            assert!(terminal.frames().iter().any(|frame| frame.contains("Unsaved changes")));
+        }
+
+        #[tokio::test]
+        async fn test_run_headless_stops_when_keys_run_out() {
+            let terminal = Headless::new(24, 80);
+            let buffer = Hub::start(Editor::with_terminal(None, Arc::new(terminal.clone())).unwrap());
+            terminal.push_key(Key::Char('x'));
+
+            let err = editor::run(buffer.clone()).await.expect_err("Input should end");
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(buffer.with(|buffer| buffer.content.clone()).await, vec!["x"]);
+        }
+    }
+
+    mod fuzz_tests {
+        use super::*;
+        use futures_util::SinkExt;
+        use proptest::prelude::*;
+        use std::io::Cursor;
+        use text_editor::buffer;
+        use tungstenite::Message;
+
+        fn key() -> impl Strategy<Value = Key> {
+            prop_oneof![
+                any::<char>().prop_map(Key::Char),
+                // Only bytes no key is bound to, letters would open prompts
+                (0x80u8..).prop_map(Key::Ctrl),
+                Just(Key::Up),
+                Just(Key::Down),
+                Just(Key::Left),
+                Just(Key::Right),
+                Just(Key::Home),
+                Just(Key::End),
+                Just(Key::Enter),
+                Just(Key::Backspace),
+                Just(Key::Delete),
+            ]
+        }
+
+        fn change() -> impl Strategy<Value = TextChange> {
+            (0usize..40, 0usize..40, "(\\PC|\n){0,5}").prop_map(|(start, end, text)| TextChange { text, start, end })
+        }
+
+        proptest! {
+            #[test]
+            fn test_decode_key_never_panics(input in proptest::collection::vec(any::<u8>(), 0..64)) {
+                let mut input = Cursor::new(input);
+                while Key::decode(&mut input).is_ok() {}
+            }
+
+            #[test]
+            fn test_decode_key_reads_typed_text(text in "[^\\x00-\\x1f\\x7f]{0,20}") {
+                let mut input = Cursor::new(text.as_bytes());
+                for c in text.chars() {
+                    prop_assert_eq!(Key::decode(&mut input).unwrap(), Key::Char(c));
+                }
+                prop_assert!(Key::decode(&mut input).is_err());
+            }
+
+            #[test]
+            fn test_index_to_line_column_round_trips(text in "(\\PC|\n){0,30}", index in 0usize..130) {
+                match index_to_line_column(&text, index) {
+                    Some((line, col)) => prop_assert_eq!(buffer::offset(&buffer::lines(&text), line, col), index),
+                    None => prop_assert!(!text.is_char_boundary(index)),
+                }
+            }
+
+            #[test]
+            fn test_remote_changes_never_panic(
+                text in "(\\PC|\n){0,30}",
+                changes in proptest::collection::vec(change(), 1..4),
+                keys in proptest::collection::vec(key(), 0..20),
+            ) {
+                let mut editor = headless_editor(None).unwrap();
+                editor.set_text(&text);
+                match editor.apply_remote_change(1, 0, &changes) {
+                    Ok(()) => {
+                        let expected = Operation::from_changes(text.len(), &changes).unwrap().apply(&text).unwrap();
+                        prop_assert_eq!(editor.text(), expected);
+                    }
+                    Err(_) => prop_assert_eq!(editor.text(), text),
+                }
+                // Whatever landed around it, the cursor stays on a character
+                for key in &keys {
+                    editor.process_keypress(key).unwrap();
+                    prop_assert!(editor.content[editor.cursor_y].is_char_boundary(editor.cursor_x));
+                    editor.refresh_screen().unwrap();
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn test_server_answers_bad_messages_with_errors() {
+            let (host, addr, server) = host("héllo");
+            let mut ws = connect(addr, "fuzz", None).await;
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Snapshot { .. })).await;
+
+            ws.send(Message::Text("{\"type\": \"Change\", \"revision\": ".to_string())).await.unwrap();
+            let WsMessage::Error { message } = next_matching(&mut ws, |m| matches!(m, WsMessage::Error { .. })).await else {
+                unreachable!()
+            };
+            assert_eq!(message, "malformed message");
+
+            // Inside the "é", then past the end
+            for (start, end) in [(2, 2), (3, 100)] {
+                let change = WsMessage::Change { revision: 0, changes: vec![TextChange { text: "x".to_string(), start, end }] };
+                ws.send(Message::Text(serde_json::to_string(&change).unwrap())).await.unwrap();
+                let WsMessage::Error { message } = next_matching(&mut ws, |m| matches!(m, WsMessage::Error { .. })).await else {
+                    unreachable!()
+                };
+                assert!(message.starts_with("change rejected"), "{}", message);
+                let WsMessage::Snapshot { text, .. } = next_matching(&mut ws, |m| matches!(m, WsMessage::Snapshot { .. })).await else {
+                    unreachable!()
+                };
+                assert_eq!(text, "héllo");
+            }
+
+            // The connection is still good for real changes
+            let change = WsMessage::Change { revision: 0, changes: vec![TextChange { text: "!".to_string(), start: 6, end: 6 }] };
+            ws.send(Message::Text(serde_json::to_string(&change).unwrap())).await.unwrap();
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Ack { .. })).await;
+            assert_eq!(host.with(|host| host.text()).await, "héllo!");
+            server.abort();
+        }
+    }
+
+    mod limits_tests {
+        use super::*;
+        use futures_util::SinkExt;
+        use std::time::{Duration, Instant};
+        use text_editor::limits::{Limits, RateLimiter};
+        use tungstenite::Message;
+
+        fn limited_host(text: &str, limits: Limits) -> (Hub, SocketAddr, JoinHandle<()>) {
+            let mut host = headless_editor(None).unwrap();
+            host.set_text(text);
+            host.limits = limits;
+            serve(host)
+        }
+
+        async fn joined(addr: SocketAddr) -> Socket {
+            let mut ws = connect(addr, "limits", None).await;
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Snapshot { .. })).await;
+            ws
+        }
+
+        async fn send_change(ws: &mut Socket, revision: u64, text: &str, start: usize) {
+            let change = WsMessage::Change { revision, changes: vec![TextChange { text: text.to_string(), start, end: start }] };
+            ws.send(Message::Text(serde_json::to_string(&change).unwrap())).await.unwrap();
+        }
+
+        async fn next_error(ws: &mut Socket) -> String {
+            let WsMessage::Error { message } = next_matching(ws, |m| matches!(m, WsMessage::Error { .. })).await else {
+                unreachable!()
+            };
+            message
+        }
+
+        #[test]
+        fn test_rate_limiter_allows_a_burst_then_paces() {
+            let start = Instant::now();
+            let mut limiter = RateLimiter::new(10, start);
+            for _ in 0..10 {
+                assert_eq!(limiter.acquire(start), Duration::ZERO);
+            }
+            assert_eq!(limiter.acquire(start), Duration::from_millis(100));
+            assert_eq!(limiter.acquire(start), Duration::from_millis(200));
+
+            // Quiet for a while, it may burst again
+            assert_eq!(limiter.acquire(start + Duration::from_secs(2)), Duration::ZERO);
+        }
+
+        #[tokio::test]
+        async fn test_oversized_message_is_dropped_with_an_error() {
+            let limits = Limits { max_message_size: 100, ..Limits::default() };
+            let (host, addr, server) = limited_host("Hello", limits);
+            let mut ws = joined(addr).await;
+
+            send_change(&mut ws, 0, &"x".repeat(200), 0).await;
+            assert!(next_error(&mut ws).await.contains("over the limit of 100"));
+            assert_eq!(host.with(|host| host.text()).await, "Hello");
+            assert_eq!(host.metrics().dropped_messages(), 1);
+
+            send_change(&mut ws, 0, "Oh, ", 0).await;
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Ack { .. })).await;
+            assert_eq!(host.with(|host| host.text()).await, "Oh, Hello");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_change_over_document_limit_is_rejected() {
+            let limits = Limits { max_document_size: 8, ..Limits::default() };
+            let (host, addr, server) = limited_host("Hello", limits);
+            let mut ws = joined(addr).await;
+
+            send_change(&mut ws, 0, " world", 5).await;
+            assert!(next_error(&mut ws).await.starts_with("change rejected: document would grow to 11 bytes"));
+            let WsMessage::Snapshot { text, .. } = next_matching(&mut ws, |m| matches!(m, WsMessage::Snapshot { .. })).await else {
+                unreachable!()
+            };
+            assert_eq!(text, "Hello");
+            assert_eq!(host.metrics().rejected_changes(), 1);
+
+            // Up to the limit is fine
+            send_change(&mut ws, 0, "!!!", 5).await;
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Ack { .. })).await;
+            assert_eq!(host.with(|host| host.text()).await, "Hello!!!");
+            server.abort();
+        }
+
+        #[tokio::test]
+        async fn test_flooding_client_is_slowed_down() {
+            let limits = Limits { messages_per_second: 20, ..Limits::default() };
+            let (host, addr, server) = limited_host("", limits);
+            let mut ws = joined(addr).await;
+
+            // A second's worth goes through at once, the rest at 20 a second
+            let start = Instant::now();
+            for revision in 0..30 {
+                send_change(&mut ws, revision, "x", revision as usize).await;
+            }
+            for _ in 0..30 {
+                next_matching(&mut ws, |m| matches!(m, WsMessage::Ack { .. })).await;
+            }
+            assert!(start.elapsed() >= Duration::from_millis(400), "took {:?}", start.elapsed());
+            assert_eq!(host.with(|host| host.text()).await, "x".repeat(30));
+            assert_eq!(host.metrics().dropped_messages(), 0);
+            server.abort();
+        }
+    }
+
+    mod logging_tests {
+        use super::*;
+        use cli::LogRotation;
+        use text_editor::logging::{self, LogTarget};
+        use tracing_subscriber::fmt::MakeWriter;
+
+        fn log_files(dir: &std::path::Path) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+            names.sort();
+            names
+        }
+
+        #[test]
+        fn test_log_file_is_created_with_its_directory() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("state/text_editor/edit.log");
+            let writer = logging::writer(&LogTarget::File { path: path.clone(), rotation: LogRotation::Never }).unwrap();
+            writer.make_writer().write_all(b"hello\n").unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "hello\n");
+        }
+
+        #[test]
+        fn test_rotated_log_file_names_carry_the_date() {
+            let dir = tempfile::tempdir().unwrap();
+            let writer = logging::writer(&LogTarget::File { path: dir.path().join("edit.log"), rotation: LogRotation::Daily }).unwrap();
+            writer.make_writer().write_all(b"hello\n").unwrap();
+            let names = log_files(dir.path());
+            assert_eq!(names.len(), 1, "{:?}", names);
+            assert!(names[0].starts_with("edit.log.20"), "{:?}", names);
+        }
+
+        #[test]
+        fn test_log_target_from_the_options() {
+            let path = std::path::Path::new("serve.log");
+            let target = logging::target(Some(path), None).unwrap();
+            assert_eq!(target, Some(LogTarget::File { path: path.to_path_buf(), rotation: LogRotation::Never }));
+            assert_eq!(logging::target(Some("-".as_ref()), None).unwrap(), Some(LogTarget::Stderr));
+            assert!(logging::target(Some("-".as_ref()), Some(LogRotation::Daily)).is_err());
+            if let Some(target) = logging::target(None, None).unwrap() {
+                assert_eq!(target, LogTarget::File { path: logging::default_log_file().unwrap(), rotation: LogRotation::Daily });
+            }
+        }
+
+        #[test]
+        fn test_unwritable_log_directory_is_an_error() {
+            let file = create_test_file("not a directory");
+            let target = LogTarget::File { path: file.path().join("edit.log"), rotation: LogRotation::Never };
+            assert!(logging::writer(&target).is_err());
+        }
+    }
+
+    mod metrics_tests {
+        use super::*;
+        use futures_util::SinkExt;
+        use std::time::{Duration, Instant};
+        use text_editor::metrics::Metrics;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tungstenite::Message;
+
+        // Status code and body of a plain HTTP request
+        async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let status = response.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap();
+            let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+            (status, body)
+        }
+
+        #[test]
+        fn test_ops_per_second_covers_the_last_window() {
+            let metrics = Metrics::default();
+            let start = Instant::now();
+            for i in 0..20 {
+                metrics.op_committed(start + Duration::from_millis(i * 100));
+            }
+            assert_eq!(metrics.ops(), 20);
+            assert_eq!(metrics.ops_per_second(start + Duration::from_secs(2)), 2.0);
+            // Old seconds fall out of the window, and their slots are reused
+            assert_eq!(metrics.ops_per_second(start + Duration::from_secs(10)), 1.0);
+            metrics.op_committed(start + Duration::from_secs(10));
+            assert_eq!(metrics.ops_per_second(start + Duration::from_secs(10)), 1.1);
+            assert_eq!(metrics.ops_per_second(start + Duration::from_secs(30)), 0.0);
+        }
+
+        #[tokio::test]
+        async fn test_health_and_metrics_report_the_session() {
+            let (host, addr, server) = host("Hello");
+
+            let (status, body) = get(addr, "/health").await;
+            assert_eq!(status, 200);
+            let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(health["status"], "ok");
+            assert_eq!(health["clients"], 0);
+            assert_eq!(health["document_size"], 5);
+
+            let mut ws = connect(addr, "metrics", None).await;
+            let change = WsMessage::Change { revision: 0, changes: vec![TextChange { text: "!".to_string(), start: 5, end: 5 }] };
+            ws.send(Message::Text(serde_json::to_string(&change).unwrap())).await.unwrap();
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Ack { .. })).await;
+            ws.send(Message::Text("nonsense".to_string())).await.unwrap();
+            next_matching(&mut ws, |m| matches!(m, WsMessage::Error { .. })).await;
+
+            let health: serde_json::Value = serde_json::from_str(&get(addr, "/health").await.1).unwrap();
+            assert_eq!(health["clients"], 1);
+            assert_eq!(health["revision"], 1);
+            assert_eq!(health["document_size"], 6);
+
+            let (status, metrics) = get(addr, "/metrics").await;
+            assert_eq!(status, 200);
+            for line in [
+                "text_editor_connected_clients 1",
+                "text_editor_document_bytes 6",
+                "text_editor_revision 1",
+                "text_editor_ops_total 1",
+                "text_editor_dropped_messages_total 1",
+                "text_editor_rejected_changes_total 0",
+                "text_editor_apply_latency_seconds_count 1",
+                "# TYPE text_editor_ops_per_second gauge",
+            ] {
+                assert!(metrics.lines().any(|l| l == line), "{} missing from\n{}", line, metrics);
+            }
+
+            // Load balancers stop sending clients once it is going away
+            host.shutdown().token.cancel();
+            assert_eq!(get(addr, "/health").await.0, 503);
+            server.abort();
         }
     }
-}
\ No newline at end of file
+}
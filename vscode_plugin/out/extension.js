@@ -3,51 +3,188 @@ Object.defineProperty(exports, "__esModule", { value: true });
 exports.deactivate = exports.activate = void 0;
 const vscode = require("vscode");
 const WebSocket = require("ws");
+const ot_1 = require("./ot");
+// Must match the heartbeat settings of the Rust editor, which pings every 10s
+const PEER_TIMEOUT = 30000;
+const RECONNECT_DELAY = 250;
+const MAX_RECONNECT_DELAY = 30000;
 let ws = null;
+let stopped = false;
 let localChange = false;
+// Set while saving the document because the host saved, not the user
+let hostSave = false;
+// The Rust editor counts UTF-8 bytes, VS Code counts UTF-16 code units
+function byteOffset(text, offset) {
+    return Buffer.byteLength(text.slice(0, offset), 'utf8');
+}
+function charOffset(text, offset) {
+    return Buffer.from(text, 'utf8').subarray(0, offset).toString('utf8').length;
+}
 function activate(context) {
     let disposable = vscode.commands.registerCommand('extension.connect', () => {
-        ws = new WebSocket('ws://localhost:3030/ws');
-        ws.onmessage = (event) => {
-            const change = JSON.parse(event.data.toString());
-            const editor = vscode.window.activeTextEditor;
-            if (editor) {
-                localChange = true;
-                const startPos = editor.document.positionAt(change.start);
-                const endPos = editor.document.positionAt(change.end);
-                const edit = new vscode.WorkspaceEdit();
-                if (change.text === '') {
-                    const range = new vscode.Range(startPos, endPos);
-                    edit.delete(editor.document.uri, range);
+        const editor = vscode.window.activeTextEditor;
+        if (!editor) {
+            vscode.window.showErrorMessage('Open the shared file before connecting.');
+            return;
+        }
+        const document = editor.document;
+        // The document as the session knows it, used to translate offsets
+        let shadow = document.getText();
+        const config = vscode.workspace.getConfiguration('collabPlugin');
+        const url = config.get('serverUrl', 'ws://localhost:3030/ws');
+        const clientId = Math.random().toString(16).slice(2);
+        const session = new ot_1.GuestSession((message) => {
+            if (ws && ws.readyState === WebSocket.OPEN) {
+                ws.send(JSON.stringify(message));
+            }
+        });
+        const applyRemote = (op) => {
+            const edit = new vscode.WorkspaceEdit();
+            for (const change of op.toChanges()) {
+                const start = document.positionAt(charOffset(shadow, change.start));
+                const end = document.positionAt(charOffset(shadow, change.end));
+                edit.replace(document.uri, new vscode.Range(start, end), change.text);
+            }
+            shadow = op.apply(shadow);
+            localChange = true;
+            vscode.workspace.applyEdit(edit).then(() => {
+                localChange = false;
+            });
+        };
+        const handleMessage = (message) => {
+            if (message.type === 'snapshot') {
+                session.reset(message.revision);
+                applyRemote(ot_1.Operation.fromChanges(Buffer.byteLength(shadow, 'utf8'), [
+                    { text: message.text, start: 0, end: Buffer.byteLength(shadow, 'utf8') },
+                ]));
+            }
+            else if (message.type === 'ack') {
+                session.ack(message.revision);
+            }
+            else if (message.type === 'resumed') {
+                session.resumed(message.revision);
+            }
+            else if (message.type === 'joined') {
+                if (message.role === 'viewer') {
+                    vscode.window.showInformationMessage('Joined as a viewer: your edits will be undone by the host.');
                 }
-                else {
-                    edit.insert(editor.document.uri, startPos, change.text);
+            }
+            else if (message.type === 'chat') {
+                vscode.window.showInformationMessage(`${message.author}: ${message.text}`);
+            }
+            else if (message.type === 'error') {
+                vscode.window.showErrorMessage(`Rust Editor: ${message.message}`);
+            }
+            else if (message.type === 'save_result') {
+                if (message.error) {
+                    vscode.window.showErrorMessage(`Rust Editor failed to save: ${message.error}`);
+                }
+                else if (message.revision === session.revision && !session.hasPending() && document.isDirty) {
+                    // Everything we have is on the host's disk, so the document is clean
+                    hostSave = true;
+                    document.save().then(() => {
+                        hostSave = false;
+                    });
                 }
-                vscode.workspace.applyEdit(edit).then(() => {
-                    localChange = false;
-                });
+            }
+            else if (message.type === 'session_end') {
+                // The host is gone for good, don't try to reconnect
+                stopped = true;
+                ws === null || ws === void 0 ? void 0 : ws.close();
+                vscode.window.showInformationMessage(`Rust Editor session ended: ${message.reason}`);
+            }
+            else if (message.type === 'change') {
+                const hostLength = session.hostLength(Buffer.byteLength(shadow, 'utf8'));
+                const op = ot_1.Operation.fromChanges(hostLength, message.changes);
+                applyRemote(session.remote(message.revision, op));
             }
         };
-        vscode.workspace.onDidChangeTextDocument((event) => {
-            if (localChange)
+        // Reconnect with exponential backoff, resuming from the last revision seen
+        let delay = RECONNECT_DELAY;
+        let resumeFrom = null;
+        const connect = () => {
+            const target = new URL(url);
+            target.searchParams.set('client', clientId);
+            target.searchParams.set('role', config.get('role', 'editor'));
+            for (const [param, setting] of [['token', 'editToken'], ['name', 'name']]) {
+                const value = config.get(setting, '');
+                if (value)
+                    target.searchParams.set(param, value);
+            }
+            if (resumeFrom !== null)
+                target.searchParams.set('revision', String(resumeFrom));
+            const socket = new WebSocket(target.toString());
+            ws = socket;
+            let timeout;
+            // The host pings regularly, so silence means the connection is dead
+            const heartbeat = () => {
+                clearTimeout(timeout);
+                timeout = setTimeout(() => socket.terminate(), PEER_TIMEOUT);
+            };
+            socket.on('open', () => {
+                delay = RECONNECT_DELAY;
+                heartbeat();
+            });
+            socket.on('ping', heartbeat);
+            socket.on('message', (data) => {
+                heartbeat();
+                handleMessage(JSON.parse(data.toString()));
+            });
+            // 'close' always follows
+            socket.on('error', () => { });
+            socket.on('close', () => {
+                clearTimeout(timeout);
+                if (stopped)
+                    return;
+                session.disconnected();
+                resumeFrom = session.revision;
+                vscode.window.setStatusBarMessage(`Disconnected from Rust Editor, reconnecting in ${delay / 1000}s`, delay);
+                setTimeout(connect, delay);
+                delay = Math.min(delay * 2, MAX_RECONNECT_DELAY);
+            });
+        };
+        stopped = false;
+        connect();
+        const subscription = vscode.workspace.onDidChangeTextDocument((event) => {
+            if (localChange || event.document !== document || event.contentChanges.length === 0)
                 return;
-            const change = event.contentChanges[0];
-            const start = event.document.offsetAt(change.range.start);
-            const end = event.document.offsetAt(change.range.end);
-            const message = JSON.stringify({ text: change.text, start, end });
-            if (ws && ws.readyState === WebSocket.OPEN) {
-                ws.send(message);
+            const changes = event.contentChanges.map((change) => {
+                const start = byteOffset(shadow, change.rangeOffset);
+                const removed = shadow.slice(change.rangeOffset, change.rangeOffset + change.rangeLength);
+                return { text: change.text, start, end: start + Buffer.byteLength(removed, 'utf8') };
+            });
+            session.local(ot_1.Operation.fromChanges(Buffer.byteLength(shadow, 'utf8'), changes));
+            shadow = document.getText();
+        });
+        context.subscriptions.push(subscription);
+        const saveSubscription = vscode.workspace.onWillSaveTextDocument((event) => {
+            if (hostSave || event.document !== document)
+                return;
+            if (!session.requestSave()) {
+                vscode.window.showWarningMessage('Disconnected from Rust Editor, the host did not save.');
             }
         });
+        context.subscriptions.push(saveSubscription);
         vscode.window.showInformationMessage('Connected to Rust Editor!');
     });
     context.subscriptions.push(disposable);
+    context.subscriptions.push(vscode.commands.registerCommand('extension.chat', async () => {
+        if (!ws || ws.readyState !== WebSocket.OPEN) {
+            vscode.window.showErrorMessage('Not connected to Rust Editor.');
+            return;
+        }
+        const text = await vscode.window.showInputBox({ prompt: 'Message to everyone in the session' });
+        if (text && ws && ws.readyState === WebSocket.OPEN) {
+            ws.send(JSON.stringify({ type: 'chat', text }));
+        }
+    }));
 }
 exports.activate = activate;
 function deactivate() {
+    stopped = true;
     if (ws) {
         ws.close();
     }
 }
 exports.deactivate = deactivate;
-//# sourceMappingURL=extension.js.map
\ No newline at end of file
+//# sourceMappingURL=extension.js.map
@@ -0,0 +1,244 @@
+"use strict";
+// Operational transformation over UTF-8 byte offsets, mirroring ot.rs in the Rust editor.
+Object.defineProperty(exports, "__esModule", { value: true });
+exports.GuestSession = exports.Operation = void 0;
+const byteLength = (text) => Buffer.byteLength(text, 'utf8');
+class Operation {
+    constructor() {
+        this.components = [];
+        this.baseLength = 0;
+        this.targetLength = 0;
+    }
+    retain(n) {
+        if (n === 0)
+            return this;
+        this.baseLength += n;
+        this.targetLength += n;
+        const last = this.components[this.components.length - 1];
+        if (last && 'retain' in last) {
+            last.retain += n;
+        }
+        else {
+            this.components.push({ retain: n });
+        }
+        return this;
+    }
+    insert(text) {
+        if (text === '')
+            return this;
+        this.targetLength += byteLength(text);
+        const n = this.components.length;
+        const last = this.components[n - 1];
+        const prev = this.components[n - 2];
+        // Keep inserts in front of deletes so equal operations compare equal
+        if (last && 'insert' in last) {
+            last.insert += text;
+        }
+        else if (last && 'delete' in last && prev && 'insert' in prev) {
+            prev.insert += text;
+        }
+        else if (last && 'delete' in last) {
+            this.components.splice(n - 1, 0, { insert: text });
+        }
+        else {
+            this.components.push({ insert: text });
+        }
+        return this;
+    }
+    delete(n) {
+        if (n === 0)
+            return this;
+        this.baseLength += n;
+        const last = this.components[this.components.length - 1];
+        if (last && 'delete' in last) {
+            last.delete += n;
+        }
+        else {
+            this.components.push({ delete: n });
+        }
+        return this;
+    }
+    static fromChanges(baseLength, changes) {
+        const sorted = [...changes].sort((a, b) => a.start - b.start || a.end - b.end);
+        const op = new Operation();
+        let pos = 0;
+        for (const change of sorted) {
+            if (change.start < pos || change.start > change.end || change.end > baseLength) {
+                throw new Error(`invalid range ${change.start}..${change.end} for a document of ${baseLength} bytes`);
+            }
+            op.retain(change.start - pos).delete(change.end - change.start).insert(change.text);
+            pos = change.end;
+        }
+        return op.retain(baseLength - pos);
+    }
+    toChanges() {
+        const changes = [];
+        let pos = 0;
+        let current = null;
+        for (const c of this.components) {
+            if ('retain' in c) {
+                if (current)
+                    changes.push(current);
+                current = null;
+                pos += c.retain;
+            }
+            else if ('insert' in c) {
+                current = current || { text: '', start: pos, end: pos };
+                current.text += c.insert;
+            }
+            else {
+                current = current || { text: '', start: pos, end: pos };
+                pos += c.delete;
+                current.end = pos;
+            }
+        }
+        if (current)
+            changes.push(current);
+        return changes;
+    }
+    apply(text) {
+        const bytes = Buffer.from(text, 'utf8');
+        if (bytes.length !== this.baseLength) {
+            throw new Error(`operation expects a document of ${this.baseLength} bytes, got ${bytes.length}`);
+        }
+        const parts = [];
+        let pos = 0;
+        for (const c of this.components) {
+            if ('retain' in c) {
+                parts.push(bytes.subarray(pos, pos + c.retain));
+                pos += c.retain;
+            }
+            else if ('insert' in c) {
+                parts.push(Buffer.from(c.insert, 'utf8'));
+            }
+            else {
+                pos += c.delete;
+            }
+        }
+        return Buffer.concat(parts).toString('utf8');
+    }
+    // Returns [a', b'] so that a then b' equals b then a'; a's inserts win ties
+    static transform(a, b) {
+        if (a.baseLength !== b.baseLength) {
+            throw new Error('both operations must apply to the same document');
+        }
+        const aPrime = new Operation();
+        const bPrime = new Operation();
+        const as = a.components.map((c) => (Object.assign({}, c)));
+        const bs = b.components.map((c) => (Object.assign({}, c)));
+        let i = 0;
+        let j = 0;
+        let ca = as[i++];
+        let cb = bs[j++];
+        while (ca || cb) {
+            if (ca && 'insert' in ca) {
+                aPrime.insert(ca.insert);
+                bPrime.retain(byteLength(ca.insert));
+                ca = as[i++];
+                continue;
+            }
+            if (cb && 'insert' in cb) {
+                aPrime.retain(byteLength(cb.insert));
+                bPrime.insert(cb.insert);
+                cb = bs[j++];
+                continue;
+            }
+            if (!ca || !cb) {
+                throw new Error('both operations must apply to the same document');
+            }
+            const lenA = 'retain' in ca ? ca.retain : ca.delete;
+            const lenB = 'retain' in cb ? cb.retain : cb.delete;
+            const n = Math.min(lenA, lenB);
+            if ('retain' in ca && 'retain' in cb) {
+                aPrime.retain(n);
+                bPrime.retain(n);
+            }
+            else if ('delete' in ca && 'retain' in cb) {
+                aPrime.delete(n);
+            }
+            else if ('retain' in ca && 'delete' in cb) {
+                bPrime.delete(n);
+            }
+            ca = lenA > n ? shorten(ca, n) : as[i++];
+            cb = lenB > n ? shorten(cb, n) : bs[j++];
+        }
+        return [aPrime, bPrime];
+    }
+}
+exports.Operation = Operation;
+function shorten(c, n) {
+    return 'retain' in c ? { retain: c.retain - n } : { delete: c.delete - n };
+}
+// Client side of the session protocol: one change in flight, the rest buffered
+class GuestSession {
+    constructor(send) {
+        this.send = send;
+        this.revision = 0;
+        this.outstanding = null;
+        this.buffer = [];
+        // False between losing the connection and the host confirming the resume
+        this.connected = true;
+    }
+    hasPending() {
+        return this.outstanding !== null;
+    }
+    // Ask the host to save the document; false while disconnected
+    requestSave() {
+        if (!this.connected)
+            return false;
+        this.send({ type: 'save' });
+        return true;
+    }
+    hostLength(localLength) {
+        return this.outstanding ? this.outstanding.baseLength : localLength;
+    }
+    local(op) {
+        if (this.outstanding) {
+            this.buffer.push(op);
+        }
+        else {
+            this.sendChange(op);
+            this.outstanding = op;
+        }
+    }
+    ack(revision) {
+        this.revision = revision;
+        this.outstanding = this.buffer.shift() || null;
+        if (this.outstanding) {
+            this.sendChange(this.outstanding);
+        }
+    }
+    remote(revision, op) {
+        if (this.outstanding) {
+            [this.outstanding, op] = Operation.transform(this.outstanding, op);
+        }
+        for (let k = 0; k < this.buffer.length; k++) {
+            [this.buffer[k], op] = Operation.transform(this.buffer[k], op);
+        }
+        this.revision = revision;
+        return op;
+    }
+    disconnected() {
+        this.connected = false;
+    }
+    // The host replayed what we missed; resend whatever never reached it
+    resumed(revision) {
+        this.revision = revision;
+        this.connected = true;
+        if (this.outstanding) {
+            this.sendChange(this.outstanding);
+        }
+    }
+    reset(revision) {
+        this.revision = revision;
+        this.outstanding = null;
+        this.buffer = [];
+        this.connected = true;
+    }
+    sendChange(op) {
+        if (!this.connected)
+            return;
+        this.send({ type: 'change', revision: this.revision, changes: op.toChanges() });
+    }
+}
+exports.GuestSession = GuestSession;
@@ -1,55 +1,187 @@
 import * as vscode from 'vscode';
 import * as WebSocket from 'ws';
+import { GuestSession, Operation, TextChange } from './ot';
+
+// Must match the heartbeat settings of the Rust editor, which pings every 10s
+const PEER_TIMEOUT = 30000;
+const RECONNECT_DELAY = 250;
+const MAX_RECONNECT_DELAY = 30000;
 
 let ws: WebSocket | null = null;
+let stopped = false;
 let localChange = false;
+// Set while saving the document because the host saved, not the user
+let hostSave = false;
+
+// The Rust editor counts UTF-8 bytes, VS Code counts UTF-16 code units
+function byteOffset(text: string, offset: number): number {
+  return Buffer.byteLength(text.slice(0, offset), 'utf8');
+}
+
+function charOffset(text: string, offset: number): number {
+  return Buffer.from(text, 'utf8').subarray(0, offset).toString('utf8').length;
+}
 
 export function activate(context: vscode.ExtensionContext) {
   let disposable = vscode.commands.registerCommand('extension.connect', () => {
-    ws = new WebSocket('ws://localhost:3030/ws');
-
-    ws.onmessage = (event) => {
-      const change = JSON.parse(event.data.toString());
-      
-      const editor = vscode.window.activeTextEditor;
-      if (editor) {
-        localChange = true;
-        const startPos = editor.document.positionAt(change.start);
-        const endPos = editor.document.positionAt(change.end);
-        const edit = new vscode.WorkspaceEdit();
-        if (change.text === '') {
-          const range = new vscode.Range(startPos, endPos);
-          edit.delete(editor.document.uri, range);
-        } else {
-          edit.insert(editor.document.uri, startPos, change.text);
+    const editor = vscode.window.activeTextEditor;
+    if (!editor) {
+      vscode.window.showErrorMessage('Open the shared file before connecting.');
+      return;
+    }
+    const document = editor.document;
+    // The document as the session knows it, used to translate offsets
+    let shadow = document.getText();
+
+    const config = vscode.workspace.getConfiguration('collabPlugin');
+    const url = config.get<string>('serverUrl', 'ws://localhost:3030/ws');
+    const clientId = Math.random().toString(16).slice(2);
+    const session = new GuestSession((message) => {
+      if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify(message));
+      }
+    });
+
+    const applyRemote = (op: Operation) => {
+      const edit = new vscode.WorkspaceEdit();
+      for (const change of op.toChanges()) {
+        const start = document.positionAt(charOffset(shadow, change.start));
+        const end = document.positionAt(charOffset(shadow, change.end));
+        edit.replace(document.uri, new vscode.Range(start, end), change.text);
+      }
+      shadow = op.apply(shadow);
+      localChange = true;
+      vscode.workspace.applyEdit(edit).then(() => {
+        localChange = false;
+      });
+    };
 
+    const handleMessage = (message: any) => {
+      if (message.type === 'snapshot') {
+        session.reset(message.revision);
+        applyRemote(Operation.fromChanges(Buffer.byteLength(shadow, 'utf8'), [
+          { text: message.text, start: 0, end: Buffer.byteLength(shadow, 'utf8') },
+        ]));
+      } else if (message.type === 'ack') {
+        session.ack(message.revision);
+      } else if (message.type === 'resumed') {
+        session.resumed(message.revision);
+      } else if (message.type === 'joined') {
+        if (message.role === 'viewer') {
+          vscode.window.showInformationMessage('Joined as a viewer: your edits will be undone by the host.');
+        }
+      } else if (message.type === 'chat') {
+        vscode.window.showInformationMessage(`${message.author}: ${message.text}`);
+      } else if (message.type === 'error') {
+        vscode.window.showErrorMessage(`Rust Editor: ${message.message}`);
+      } else if (message.type === 'save_result') {
+        if (message.error) {
+          vscode.window.showErrorMessage(`Rust Editor failed to save: ${message.error}`);
+        } else if (message.revision === session.revision && !session.hasPending() && document.isDirty) {
+          // Everything we have is on the host's disk, so the document is clean
+          hostSave = true;
+          document.save().then(() => {
+            hostSave = false;
+          });
         }
-        
-        vscode.workspace.applyEdit(edit).then(() => {
-          localChange = false;
-        });
+      } else if (message.type === 'session_end') {
+        // The host is gone for good, don't try to reconnect
+        stopped = true;
+        ws?.close();
+        vscode.window.showInformationMessage(`Rust Editor session ended: ${message.reason}`);
+      } else if (message.type === 'change') {
+        const hostLength = session.hostLength(Buffer.byteLength(shadow, 'utf8'));
+        const op = Operation.fromChanges(hostLength, message.changes);
+        applyRemote(session.remote(message.revision, op));
       }
     };
 
-    vscode.workspace.onDidChangeTextDocument((event) => {
-      if (localChange) return;
+    // Reconnect with exponential backoff, resuming from the last revision seen
+    let delay = RECONNECT_DELAY;
+    let resumeFrom: number | null = null;
+    const connect = () => {
+      const target = new URL(url);
+      target.searchParams.set('client', clientId);
+      target.searchParams.set('role', config.get<string>('role', 'editor'));
+      for (const [param, setting] of [['token', 'editToken'], ['name', 'name']]) {
+        const value = config.get<string>(setting, '');
+        if (value) target.searchParams.set(param, value);
+      }
+      if (resumeFrom !== null) target.searchParams.set('revision', String(resumeFrom));
+
+      const socket = new WebSocket(target.toString());
+      ws = socket;
+      let timeout: NodeJS.Timeout | undefined;
+      // The host pings regularly, so silence means the connection is dead
+      const heartbeat = () => {
+        clearTimeout(timeout);
+        timeout = setTimeout(() => socket.terminate(), PEER_TIMEOUT);
+      };
 
-      const change = event.contentChanges[0];
-      const start = event.document.offsetAt(change.range.start);
-      const end = event.document.offsetAt(change.range.end);
-      const message = JSON.stringify({ text: change.text, start, end });
-      if (ws && ws.readyState === WebSocket.OPEN) {
-        ws.send(message);
+      socket.on('open', () => {
+        delay = RECONNECT_DELAY;
+        heartbeat();
+      });
+      socket.on('ping', heartbeat);
+      socket.on('message', (data) => {
+        heartbeat();
+        handleMessage(JSON.parse(data.toString()));
+      });
+      // 'close' always follows
+      socket.on('error', () => {});
+      socket.on('close', () => {
+        clearTimeout(timeout);
+        if (stopped) return;
+        session.disconnected();
+        resumeFrom = session.revision;
+        vscode.window.setStatusBarMessage(`Disconnected from Rust Editor, reconnecting in ${delay / 1000}s`, delay);
+        setTimeout(connect, delay);
+        delay = Math.min(delay * 2, MAX_RECONNECT_DELAY);
+      });
+    };
+    stopped = false;
+    connect();
+
+    const subscription = vscode.workspace.onDidChangeTextDocument((event) => {
+      if (localChange || event.document !== document || event.contentChanges.length === 0) return;
+
+      const changes: TextChange[] = event.contentChanges.map((change) => {
+        const start = byteOffset(shadow, change.rangeOffset);
+        const removed = shadow.slice(change.rangeOffset, change.rangeOffset + change.rangeLength);
+        return { text: change.text, start, end: start + Buffer.byteLength(removed, 'utf8') };
+      });
+      session.local(Operation.fromChanges(Buffer.byteLength(shadow, 'utf8'), changes));
+      shadow = document.getText();
+    });
+    context.subscriptions.push(subscription);
+
+    const saveSubscription = vscode.workspace.onWillSaveTextDocument((event) => {
+      if (hostSave || event.document !== document) return;
+      if (!session.requestSave()) {
+        vscode.window.showWarningMessage('Disconnected from Rust Editor, the host did not save.');
       }
     });
+    context.subscriptions.push(saveSubscription);
 
     vscode.window.showInformationMessage('Connected to Rust Editor!');
   });
 
   context.subscriptions.push(disposable);
+
+  context.subscriptions.push(vscode.commands.registerCommand('extension.chat', async () => {
+    if (!ws || ws.readyState !== WebSocket.OPEN) {
+      vscode.window.showErrorMessage('Not connected to Rust Editor.');
+      return;
+    }
+    const text = await vscode.window.showInputBox({ prompt: 'Message to everyone in the session' });
+    if (text && ws && ws.readyState === WebSocket.OPEN) {
+      ws.send(JSON.stringify({ type: 'chat', text }));
+    }
+  }));
 }
 
 export function deactivate() {
+  stopped = true;
   if (ws) {
     ws.close();
   }
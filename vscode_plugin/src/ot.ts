@@ -0,0 +1,247 @@
+// Operational transformation over UTF-8 byte offsets, mirroring ot.rs in the Rust editor.
+
+export interface TextChange {
+  text: string;
+  start: number;
+  end: number;
+}
+
+type Component = { retain: number } | { insert: string } | { delete: number };
+
+const byteLength = (text: string) => Buffer.byteLength(text, 'utf8');
+
+export class Operation {
+  components: Component[] = [];
+  baseLength = 0;
+  targetLength = 0;
+
+  retain(n: number): Operation {
+    if (n === 0) return this;
+    this.baseLength += n;
+    this.targetLength += n;
+    const last = this.components[this.components.length - 1];
+    if (last && 'retain' in last) {
+      last.retain += n;
+    } else {
+      this.components.push({ retain: n });
+    }
+    return this;
+  }
+
+  insert(text: string): Operation {
+    if (text === '') return this;
+    this.targetLength += byteLength(text);
+    const n = this.components.length;
+    const last = this.components[n - 1];
+    const prev = this.components[n - 2];
+    // Keep inserts in front of deletes so equal operations compare equal
+    if (last && 'insert' in last) {
+      last.insert += text;
+    } else if (last && 'delete' in last && prev && 'insert' in prev) {
+      prev.insert += text;
+    } else if (last && 'delete' in last) {
+      this.components.splice(n - 1, 0, { insert: text });
+    } else {
+      this.components.push({ insert: text });
+    }
+    return this;
+  }
+
+  delete(n: number): Operation {
+    if (n === 0) return this;
+    this.baseLength += n;
+    const last = this.components[this.components.length - 1];
+    if (last && 'delete' in last) {
+      last.delete += n;
+    } else {
+      this.components.push({ delete: n });
+    }
+    return this;
+  }
+
+  static fromChanges(baseLength: number, changes: TextChange[]): Operation {
+    const sorted = [...changes].sort((a, b) => a.start - b.start || a.end - b.end);
+    const op = new Operation();
+    let pos = 0;
+    for (const change of sorted) {
+      if (change.start < pos || change.start > change.end || change.end > baseLength) {
+        throw new Error(`invalid range ${change.start}..${change.end} for a document of ${baseLength} bytes`);
+      }
+      op.retain(change.start - pos).delete(change.end - change.start).insert(change.text);
+      pos = change.end;
+    }
+    return op.retain(baseLength - pos);
+  }
+
+  toChanges(): TextChange[] {
+    const changes: TextChange[] = [];
+    let pos = 0;
+    let current: TextChange | null = null;
+    for (const c of this.components) {
+      if ('retain' in c) {
+        if (current) changes.push(current);
+        current = null;
+        pos += c.retain;
+      } else if ('insert' in c) {
+        current = current || { text: '', start: pos, end: pos };
+        current.text += c.insert;
+      } else {
+        current = current || { text: '', start: pos, end: pos };
+        pos += c.delete;
+        current.end = pos;
+      }
+    }
+    if (current) changes.push(current);
+    return changes;
+  }
+
+  apply(text: string): string {
+    const bytes = Buffer.from(text, 'utf8');
+    if (bytes.length !== this.baseLength) {
+      throw new Error(`operation expects a document of ${this.baseLength} bytes, got ${bytes.length}`);
+    }
+    const parts: Buffer[] = [];
+    let pos = 0;
+    for (const c of this.components) {
+      if ('retain' in c) {
+        parts.push(bytes.subarray(pos, pos + c.retain));
+        pos += c.retain;
+      } else if ('insert' in c) {
+        parts.push(Buffer.from(c.insert, 'utf8'));
+      } else {
+        pos += c.delete;
+      }
+    }
+    return Buffer.concat(parts).toString('utf8');
+  }
+
+  // Returns [a', b'] so that a then b' equals b then a'; a's inserts win ties
+  static transform(a: Operation, b: Operation): [Operation, Operation] {
+    if (a.baseLength !== b.baseLength) {
+      throw new Error('both operations must apply to the same document');
+    }
+    const aPrime = new Operation();
+    const bPrime = new Operation();
+    const as = a.components.map((c) => ({ ...c }));
+    const bs = b.components.map((c) => ({ ...c }));
+    let i = 0;
+    let j = 0;
+    let ca: Component | undefined = as[i++];
+    let cb: Component | undefined = bs[j++];
+
+    while (ca || cb) {
+      if (ca && 'insert' in ca) {
+        aPrime.insert(ca.insert);
+        bPrime.retain(byteLength(ca.insert));
+        ca = as[i++];
+        continue;
+      }
+      if (cb && 'insert' in cb) {
+        aPrime.retain(byteLength(cb.insert));
+        bPrime.insert(cb.insert);
+        cb = bs[j++];
+        continue;
+      }
+      if (!ca || !cb) {
+        throw new Error('both operations must apply to the same document');
+      }
+      const lenA = 'retain' in ca ? ca.retain : ca.delete;
+      const lenB = 'retain' in cb ? cb.retain : cb.delete;
+      const n = Math.min(lenA, lenB);
+      if ('retain' in ca && 'retain' in cb) {
+        aPrime.retain(n);
+        bPrime.retain(n);
+      } else if ('delete' in ca && 'retain' in cb) {
+        aPrime.delete(n);
+      } else if ('retain' in ca && 'delete' in cb) {
+        bPrime.delete(n);
+      }
+      ca = lenA > n ? shorten(ca, n) : as[i++];
+      cb = lenB > n ? shorten(cb, n) : bs[j++];
+    }
+    return [aPrime, bPrime];
+  }
+}
+
+function shorten(c: Component, n: number): Component {
+  return 'retain' in c ? { retain: c.retain - n } : { delete: (c as { delete: number }).delete - n };
+}
+
+// Client side of the session protocol: one change in flight, the rest buffered
+export class GuestSession {
+  revision = 0;
+  private outstanding: Operation | null = null;
+  private buffer: Operation[] = [];
+  // False between losing the connection and the host confirming the resume
+  private connected = true;
+
+  constructor(private send: (message: object) => void) {}
+
+  hasPending(): boolean {
+    return this.outstanding !== null;
+  }
+
+  // Ask the host to save the document; false while disconnected
+  requestSave(): boolean {
+    if (!this.connected) return false;
+    this.send({ type: 'save' });
+    return true;
+  }
+
+  hostLength(localLength: number): number {
+    return this.outstanding ? this.outstanding.baseLength : localLength;
+  }
+
+  local(op: Operation) {
+    if (this.outstanding) {
+      this.buffer.push(op);
+    } else {
+      this.sendChange(op);
+      this.outstanding = op;
+    }
+  }
+
+  ack(revision: number) {
+    this.revision = revision;
+    this.outstanding = this.buffer.shift() || null;
+    if (this.outstanding) {
+      this.sendChange(this.outstanding);
+    }
+  }
+
+  remote(revision: number, op: Operation): Operation {
+    if (this.outstanding) {
+      [this.outstanding, op] = Operation.transform(this.outstanding, op);
+    }
+    for (let k = 0; k < this.buffer.length; k++) {
+      [this.buffer[k], op] = Operation.transform(this.buffer[k], op);
+    }
+    this.revision = revision;
+    return op;
+  }
+
+  disconnected() {
+    this.connected = false;
+  }
+
+  // The host replayed what we missed; resend whatever never reached it
+  resumed(revision: number) {
+    this.revision = revision;
+    this.connected = true;
+    if (this.outstanding) {
+      this.sendChange(this.outstanding);
+    }
+  }
+
+  reset(revision: number) {
+    this.revision = revision;
+    this.outstanding = null;
+    this.buffer = [];
+    this.connected = true;
+  }
+
+  private sendChange(op: Operation) {
+    if (!this.connected) return;
+    this.send({ type: 'change', revision: this.revision, changes: op.toChanges() });
+  }
+}